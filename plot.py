@@ -1,11 +1,15 @@
 #!/usr/bin/python
 
+import sys
+
 import pandas as pd
 import seaborn as sns
 
 from matplotlib import pyplot as plt
 
-df = pd.read_csv('log')
+# written by the simulator with
+#   cargo run --release --bin console -- moving gossip --runs 10 --output out/results.csv
+df = pd.read_csv(sys.argv[1] if len(sys.argv) > 1 else 'out/results.csv')
 
 sns.set_theme(style='whitegrid')
 
@@ -13,7 +17,7 @@ for column in ['received', 'transmitted', 'avg_time']:
     sns.boxplot(x='sent', y=column, hue='algorithm', data=df) \
        .get_figure().savefig('out/{}.png'.format(column))
     plt.clf()
-    for algo in ['dsdv', 'gossip']:
+    for algo in df.algorithm.unique():
         sns.boxplot(x='sent', y=column, data=df[df.algorithm == algo]) \
            .get_figure().savefig('out/{}.{}.png'.format(column, algo))
         plt.clf()
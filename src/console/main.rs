@@ -1,12 +1,144 @@
 extern crate core;
 
-#[path = "../mod.rs"]
-pub mod main;
+pub use radio_message::*;
 
-pub use main::*;
+use clap::{Args, Parser, Subcommand};
+use scenarios::complete::CompleteModelParams;
+use scenarios::experiment::{Experiment, ProtocolName, ScenarioParams};
+use scenarios::moving::MovingModelParams;
+use scenarios::sparse::SparseModelParams;
+use scenarios::sweep::Sweep;
+use std::path::PathBuf;
 
-#[cfg(test)]
-#[path = "../scenarios/mod.rs"]
-mod scenarios;
+/// Simulates delivering messages over a radio network
+#[derive(Parser)]
+struct Cli {
+    #[command(subcommand)]
+    scenario: Scenario,
+}
 
-fn main() {}
+#[derive(Subcommand)]
+enum Scenario {
+    /// Static nodes that all hear each other over lossy links
+    Complete {
+        #[arg(value_enum)]
+        protocol: ProtocolName,
+        #[command(flatten)]
+        output: Output,
+        #[command(flatten)]
+        params: CompleteModelParams,
+    },
+    /// Agents moving around a square field, in range of the close ones only
+    Moving {
+        #[arg(value_enum)]
+        protocol: ProtocolName,
+        #[command(flatten)]
+        output: Output,
+        #[command(flatten)]
+        params: Box<MovingModelParams>,
+    },
+    /// Static sparse networks: random geometric graphs, lines and chains
+    Sparse {
+        #[arg(value_enum)]
+        protocol: ProtocolName,
+        #[command(flatten)]
+        output: Output,
+        #[command(flatten)]
+        params: SparseModelParams,
+    },
+    /// Runs the experiment described by a scenario file
+    Run {
+        file: PathBuf,
+        /// Overrides the output of the file
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+        /// Overrides the trace of the file
+        #[arg(long)]
+        trace: Option<PathBuf>,
+    },
+    /// Runs the experiments of a sweep file in parallel, skipping the runs
+    /// already in its output
+    Sweep {
+        file: PathBuf,
+        /// Overrides the output of the file
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+        /// Threads to run on, all the cores by default
+        #[arg(long)]
+        threads: Option<usize>,
+    },
+}
+
+#[derive(Args)]
+struct Output {
+    /// How many times to run the scenario, run i gets seed + i
+    #[arg(long, default_value_t = 1)]
+    runs: u64,
+    /// csv or jsonl file to append the results to
+    #[arg(long, short)]
+    output: Option<PathBuf>,
+    /// File to write the trace of the run to, several runs get the seed
+    /// added to the name
+    #[arg(long)]
+    trace: Option<PathBuf>,
+}
+
+impl Output {
+    fn experiment(self, scenario: ScenarioParams, protocol: ProtocolName) -> Experiment {
+        Experiment {
+            scenario,
+            protocol: protocol.params(),
+            runs: self.runs,
+            output: self.output,
+            trace: self.trace,
+        }
+    }
+}
+
+fn main() -> Result<(), anyhow::Error> {
+    env_logger::init();
+
+    let experiment = match Cli::parse().scenario {
+        Scenario::Complete {
+            protocol,
+            output,
+            params,
+        } => output.experiment(ScenarioParams::Complete(params), protocol),
+        Scenario::Moving {
+            protocol,
+            output,
+            params,
+        } => output.experiment(ScenarioParams::Moving(params), protocol),
+        Scenario::Sparse {
+            protocol,
+            output,
+            params,
+        } => output.experiment(ScenarioParams::Sparse(params), protocol),
+        Scenario::Run {
+            file,
+            output,
+            trace,
+        } => {
+            let mut experiment = Experiment::load(file)?;
+            if output.is_some() {
+                experiment.output = output;
+            }
+            if trace.is_some() {
+                experiment.trace = trace;
+            }
+            experiment
+        }
+        Scenario::Sweep {
+            file,
+            output,
+            threads,
+        } => {
+            let mut sweep = Sweep::load(file)?;
+            if let Some(output) = output {
+                sweep.output = output;
+            }
+            return sweep.run(threads);
+        }
+    };
+    experiment.run()
+}
@@ -0,0 +1,232 @@
+use crate::model::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+/// Result of a single run together with everything needed to repeat it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Record<T> {
+    pub protocol: String,
+    pub seed: u64,
+    pub params: T,
+    pub stats: Stats,
+}
+
+fn cell(value: Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(value) => value,
+        value => value.to_string(),
+    }
+}
+
+impl<T> Record<T> {
+    /// One line for the terminal: protocol, seed, messages received by the
+    /// nodes, messages delivered and the average delivery time
+    pub fn summary(&self) -> String {
+        format!(
+            "{} {} {} {} {}",
+            self.protocol,
+            self.seed,
+            self.stats.total,
+            self.stats.delivered,
+            // NaN if nothing was delivered
+            self.stats.avg_delivery_time().unwrap_or(f64::NAN)
+        )
+    }
+}
+
+impl<T: Serialize> Record<T> {
+    pub fn new<P: Protocol>(seed: u64, params: T, stats: Stats) -> Record<T> {
+        Record {
+            protocol: P::NAME.to_string(),
+            seed,
+            params,
+            stats,
+        }
+    }
+
+    /// Flat columns of the run: the fields of the parameters and the summary
+    /// of the stats, without the per-message and per-node parts. The first
+    /// columns are the ones of the old `out/log.csv`
+    pub fn row(&self) -> Result<Vec<(String, String)>, anyhow::Error> {
+        let stats = &self.stats;
+        let optional = |value: Option<f64>| value.map(|v| v.to_string()).unwrap_or_default();
+        let mut row = vec![
+            ("algorithm".to_string(), self.protocol.clone()),
+            ("sent".to_string(), stats.messages.len().to_string()),
+            ("transmitted".to_string(), stats.total.to_string()),
+            ("received".to_string(), stats.delivered.to_string()),
+            ("avg_time".to_string(), optional(stats.avg_delivery_time())),
+            ("seed".to_string(), self.seed.to_string()),
+        ];
+        match serde_json::to_value(&self.params)? {
+            Value::Object(params) => {
+                for (name, value) in params {
+                    // like the seed, which is there already
+                    if row.iter().all(|(column, _)| *column != name) {
+                        row.push((name, cell(value)));
+                    }
+                }
+            }
+            Value::Null => {}
+            value => row.push(("params".to_string(), cell(value))),
+        }
+        let percentile = |percent| optional(stats.latency_percentile(percent).map(f64::from));
+        let summary = [
+            ("delivery_ratio", stats.delivery_ratio().to_string()),
+            ("p50", percentile(50.)),
+            ("p90", percentile(90.)),
+            ("p99", percentile(99.)),
+            ("avg_hops", optional(stats.avg_hops())),
+            ("duplicates", stats.duplicates.to_string()),
+            ("broadcasts", stats.broadcasts.to_string()),
+            ("unicasts", stats.unicasts.to_string()),
+            ("bytes_sent", stats.bytes_sent.to_string()),
+            ("bytes_received", stats.bytes_received.to_string()),
+            ("data_transmissions", stats.data.transmissions.to_string()),
+            ("data_bytes", stats.data.bytes.to_string()),
+            (
+                "control_transmissions",
+                stats.control.transmissions.to_string(),
+            ),
+            ("control_bytes", stats.control.bytes.to_string()),
+            ("collisions", stats.collisions.to_string()),
+            ("backoffs", stats.backoffs.to_string()),
+            ("dropped", stats.dropped.to_string()),
+            ("one_way", stats.one_way.to_string()),
+            ("one_way_unicasts", stats.one_way_unicasts.to_string()),
+            ("dead", stats.dead.to_string()),
+            ("first_death", optional(stats.first_death.map(f64::from))),
+            ("lifetime", optional(stats.lifetime.map(f64::from))),
+        ];
+        row.extend(summary.map(|(name, value)| (name.to_string(), value)));
+        Ok(row)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// One row per run with the summary only
+    Csv,
+    /// One json object per line with the whole `Record`
+    Jsonl,
+}
+
+impl Format {
+    pub fn from_path(path: &Path) -> Result<Format, anyhow::Error> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("csv") => Ok(Format::Csv),
+            Some("jsonl") | Some("json") => Ok(Format::Jsonl),
+            _ => anyhow::bail!("unknown output format of {}", path.display()),
+        }
+    }
+}
+
+/// Appends the records of the runs to a file as they finish
+pub struct Exporter {
+    format: Format,
+    writer: BufWriter<File>,
+    // columns of the csv file, once it has them
+    header: Option<Vec<String>>,
+}
+
+impl Exporter {
+    /// Opens `path` for appending, the format is picked by the extension. A
+    /// record cut in the middle by an interrupted run is dropped
+    pub fn create(path: impl AsRef<Path>) -> Result<Exporter, anyhow::Error> {
+        let path = path.as_ref();
+        let format = Format::from_path(path)?;
+        if let Ok(contents) = std::fs::read(path) {
+            if !contents.is_empty() && !contents.ends_with(b"\n") {
+                let end = contents
+                    .iter()
+                    .rposition(|&c| c == b'\n')
+                    .map_or(0, |i| i + 1);
+                OpenOptions::new()
+                    .write(true)
+                    .open(path)?
+                    .set_len(end as u64)?;
+            }
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let header = match format {
+            Format::Csv if file.metadata()?.len() > 0 => Some(
+                csv::Reader::from_path(path)?
+                    .headers()?
+                    .iter()
+                    .map(String::from)
+                    .collect(),
+            ),
+            _ => None,
+        };
+        Ok(Exporter {
+            format,
+            writer: BufWriter::new(file),
+            header,
+        })
+    }
+
+    pub fn write<T: Serialize>(&mut self, record: &Record<T>) -> Result<(), anyhow::Error> {
+        match self.format {
+            Format::Csv => {
+                let row = record.row()?;
+                let columns: Vec<_> = row.iter().map(|(name, _)| name.clone()).collect();
+                let mut writer = csv::Writer::from_writer(&mut self.writer);
+                match &self.header {
+                    Some(header) if *header != columns => {
+                        anyhow::bail!("the run doesn't have the columns of the file")
+                    }
+                    Some(_) => {}
+                    None => {
+                        writer.write_record(&columns)?;
+                        self.header = Some(columns);
+                    }
+                }
+                writer.write_record(row.iter().map(|(_, value)| value))?;
+                writer.flush()?;
+            }
+            Format::Jsonl => {
+                serde_json::to_writer(&mut self.writer, record)?;
+                writeln!(self.writer)?;
+            }
+        }
+        // every finished run is on disk even if the following ones crash
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Seeds of the runs already in the file at `path`, none if there is no file
+pub fn read_seeds(path: impl AsRef<Path>) -> Result<HashSet<u64>, anyhow::Error> {
+    let path = path.as_ref();
+    let mut seeds = HashSet::new();
+    if !path.exists() {
+        return Ok(seeds);
+    }
+    match Format::from_path(path)? {
+        Format::Csv => {
+            let mut reader = csv::ReaderBuilder::new().flexible(true).from_path(path)?;
+            let column = reader.headers()?.iter().position(|name| name == "seed");
+            let column = column.ok_or_else(|| anyhow::anyhow!("no seed column"))?;
+            for row in reader.records() {
+                // the last one may be cut short
+                if let Some(seed) = row.ok().and_then(|row| row.get(column)?.parse().ok()) {
+                    seeds.insert(seed);
+                }
+            }
+        }
+        Format::Jsonl => {
+            for line in BufReader::new(File::open(path)?).lines() {
+                let record = serde_json::from_str::<Value>(&line?);
+                if let Some(seed) = record.ok().and_then(|record| record["seed"].as_u64()) {
+                    seeds.insert(seed);
+                }
+            }
+        }
+    }
+    Ok(seeds)
+}
@@ -1,2 +1,4 @@
+pub mod export;
 pub mod model;
 pub mod protocols;
+pub mod scenarios;
@@ -0,0 +1,69 @@
+use super::*;
+use rand::distributions::Distribution;
+use rand::RngCore;
+
+/// Crashes and restarts nodes: every node stays up for a time drawn from
+/// `up`, then down for a time drawn from `down`, and so on.
+///
+/// Durations are in steps, rounded and at least one step.
+pub struct Churn<U, D> {
+    up: U,
+    down: D,
+    // whether restarted nodes keep the state they had before the crash
+    keep_state: bool,
+    // node -> (step of its next crash or restart, whether it is up now)
+    next: Vec<(u32, bool)>,
+}
+
+fn duration(distribution: &impl Distribution<f64>, rng: &mut impl RngCore) -> u32 {
+    distribution.sample(rng).round().max(1.) as u32
+}
+
+impl<U: Distribution<f64>, D: Distribution<f64>> Churn<U, D> {
+    /// Starts counting the up-times of all nodes from the current step
+    pub fn new<P: Protocol, R: RngCore>(
+        model: &mut Model<P, R>,
+        up: U,
+        down: D,
+        keep_state: bool,
+    ) -> Churn<U, D> {
+        let start = model.current_step();
+        let next = (0..model.size())
+            .map(|_| (start + duration(&up, &mut model.rng), true))
+            .collect();
+        Churn {
+            up,
+            down,
+            keep_state,
+            next,
+        }
+    }
+
+    /// The first step some node crashes or restarts at
+    pub fn next_event(&self) -> Option<u32> {
+        self.next.iter().map(|(at, _)| *at).min()
+    }
+
+    /// Crashes and restarts the nodes that are due at `step` or earlier, call
+    /// it before the model processes `step`. The model is first brought to the
+    /// step right before, so restarted nodes start at the same step no matter
+    /// how the model is advanced
+    pub fn apply<P: Protocol, R: RngCore>(&mut self, model: &mut Model<P, R>, step: u32) {
+        if self.next_event().is_none_or(|next| next > step) {
+            return;
+        }
+        model.run_until(step - 1);
+        for id in 0..self.next.len() {
+            while self.next[id].0 <= step {
+                let (at, up) = self.next[id];
+                if up {
+                    model.kill_node(id as u32);
+                    self.next[id] = (at + duration(&self.down, &mut model.rng), false);
+                } else {
+                    model.restart_node(id as u32, self.keep_state);
+                    self.next[id] = (at + duration(&self.up, &mut model.rng), true);
+                }
+            }
+        }
+    }
+}
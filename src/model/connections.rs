@@ -1,17 +1,236 @@
 use std::collections::HashMap;
+use std::path::Path;
 
-use rand_distr::Distribution;
+use anyhow::{anyhow, bail};
+use rand::Rng;
+use rand_distr::{Distribution, Exp, LogNormal, Normal, Uniform};
 
-const MIN_DELAY: i32 = 10;
-const DEFAULT_DELAY: i32 = 50;
-const DEVIATION: i32 = 10;
+const MIN_DELAY: u32 = 10;
+const DEFAULT_DELAY: f32 = 50.;
+const DEVIATION: f32 = 10.;
 
-#[derive(Default, Clone)]
+/// Delays measured on a real link, binned. The delay falls in a bin with the
+/// chance of its weight, anywhere within it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    // bounds of the bins, one more than there are bins
+    edges: Vec<f32>,
+    weights: Vec<f32>,
+    total: f32,
+}
+
+impl Histogram {
+    /// Bins from `edges[i]` to `edges[i + 1]` with the chance of `weights[i]`
+    pub fn new(edges: Vec<f32>, weights: Vec<f32>) -> Result<Histogram, anyhow::Error> {
+        if edges.len() != weights.len() + 1 {
+            bail!("{} bins need {} edges", weights.len(), weights.len() + 1);
+        }
+        if edges.windows(2).any(|pair| pair[1] < pair[0]) {
+            bail!("the edges of the bins go back");
+        }
+        if weights.iter().any(|&weight| weight < 0.) {
+            bail!("negative weight of a bin");
+        }
+        let total = weights.iter().sum();
+        if total <= 0. {
+            bail!("the bins are empty");
+        }
+        Ok(Histogram {
+            edges,
+            weights,
+            total,
+        })
+    }
+
+    /// `bins` bins of the same width from the shortest to the longest of the
+    /// delays
+    pub fn from_samples(samples: &[f32], bins: usize) -> Result<Histogram, anyhow::Error> {
+        if samples.is_empty() || bins == 0 {
+            bail!("no samples or no bins");
+        }
+        let min = samples.iter().cloned().fold(f32::INFINITY, f32::min);
+        let max = samples.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+        let width = (max - min) / bins as f32;
+        let edges = (0..=bins).map(|i| min + width * i as f32).collect();
+        let mut weights = vec![0.; bins];
+        for &sample in samples {
+            let bin = if width > 0. {
+                ((sample - min) / width) as usize
+            } else {
+                0
+            };
+            weights[bin.min(bins - 1)] += 1.;
+        }
+        Histogram::new(edges, weights)
+    }
+
+    /// Reads measured delays, in steps and separated by whitespace, into
+    /// `bins` bins
+    pub fn load(path: impl AsRef<Path>, bins: usize) -> Result<Histogram, anyhow::Error> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| anyhow!("can't open {}: {}", path.display(), err))?;
+        let samples = text
+            .split_whitespace()
+            .map(|value| {
+                value
+                    .parse::<f32>()
+                    .map_err(|_| anyhow!("bad delay {:?} in {}", value, path.display()))
+            })
+            .collect::<Result<Vec<f32>, _>>()?;
+        Histogram::from_samples(&samples, bins)
+    }
+
+    pub fn sample(&self, rng: &mut impl Rng) -> f32 {
+        let mut pick = rng.gen_range(0.0..self.total);
+        let mut bin = 0;
+        while bin + 1 < self.weights.len() && pick >= self.weights[bin] {
+            pick -= self.weights[bin];
+            bin += 1;
+        }
+        let (low, high) = (self.edges[bin], self.edges[bin + 1]);
+        low + (high - low) * rng.gen::<f32>()
+    }
+}
+
+/// How the delay of a link is spread, in steps. Panics on sampling if the
+/// parameters don't make sense, like a negative deviation
+#[derive(Debug, Clone, PartialEq)]
+pub enum DelayDistribution {
+    Constant(f32),
+    /// Anywhere from `min` to `max`
+    Uniform {
+        min: f32,
+        max: f32,
+    },
+    Normal {
+        mean: f32,
+        deviation: f32,
+    },
+    Exponential {
+        mean: f32,
+    },
+    /// The logarithm of the delay is normal with `mu` and `sigma`, a long
+    /// tail of late messages
+    LogNormal {
+        mu: f32,
+        sigma: f32,
+    },
+    Histogram(Histogram),
+}
+
+impl Default for DelayDistribution {
+    fn default() -> Self {
+        DelayDistribution::Normal {
+            mean: DEFAULT_DELAY,
+            deviation: DEVIATION,
+        }
+    }
+}
+
+impl DelayDistribution {
+    pub fn sample(&self, rng: &mut impl Rng) -> f32 {
+        match self {
+            &DelayDistribution::Constant(delay) => delay,
+            &DelayDistribution::Uniform { min, max } => {
+                Uniform::new_inclusive(min, max).sample(rng)
+            }
+            &DelayDistribution::Normal { mean, deviation } => {
+                Normal::new(mean, deviation).unwrap().sample(rng)
+            }
+            &DelayDistribution::Exponential { mean } => Exp::new(1. / mean).unwrap().sample(rng),
+            &DelayDistribution::LogNormal { mu, sigma } => {
+                LogNormal::new(mu, sigma).unwrap().sample(rng)
+            }
+            DelayDistribution::Histogram(histogram) => histogram.sample(rng),
+        }
+    }
+}
+
+/// Gilbert-Elliott bursty loss: a link flips between a good and a bad state
+/// with the given chances every step, and loses messages with the chance of
+/// its state on top of the ones its probability loses
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GilbertElliott {
+    // chance to go bad on a step in the good state
+    pub to_bad: f64,
+    // chance to recover on a step in the bad state
+    pub to_good: f64,
+    pub good_loss: f64,
+    pub bad_loss: f64,
+}
+
+impl GilbertElliott {
+    /// Bad bursts of `bad_length` steps on average, losing everything,
+    /// between `good_length` steps on average without extra losses
+    pub fn new(good_length: f64, bad_length: f64) -> Self {
+        GilbertElliott {
+            to_bad: 1. / good_length.max(1.),
+            to_good: 1. / bad_length.max(1.),
+            good_loss: 0.,
+            bad_loss: 1.,
+        }
+    }
+
+    /// Part of the time the link is bad in the long run
+    pub fn bad_share(&self) -> f64 {
+        if self.to_bad + self.to_good > 0. {
+            self.to_bad / (self.to_bad + self.to_good)
+        } else {
+            0.
+        }
+    }
+
+    // chance to be bad `steps` steps after being bad or good
+    fn bad_after(&self, bad: bool, steps: u32) -> f64 {
+        let share = self.bad_share();
+        // how much of the state is still remembered
+        let memory = (1. - self.to_bad - self.to_good).powi(steps.min(i32::MAX as u32) as i32);
+        let chance = if bad {
+            share + (1. - share) * memory
+        } else {
+            share * (1. - memory)
+        };
+        chance.clamp(0., 1.)
+    }
+}
+
+#[derive(Clone)]
 pub struct ConnMap {
     // probability that message will be delivered, [0; 1]
     pub prob: HashMap<(u32, u32), f32>,
     // shift in delay distribution
     pub delay: HashMap<(u32, u32), i32>,
+    // bytes per step, links without one transmit instantly
+    pub bandwidth: HashMap<(u32, u32), f32>,
+    // delay distributions of the links that don't follow the default one
+    pub distributions: HashMap<(u32, u32), DelayDistribution>,
+    pub default_distribution: DelayDistribution,
+    // shorter delays are stretched to it, a message can't arrive on the
+    // step it's sent
+    min_delay: u32,
+    // bursty loss of the links that have their own
+    pub bursts: HashMap<(u32, u32), GilbertElliott>,
+    // bursty loss of the other links, none loses messages independently
+    pub default_bursts: Option<GilbertElliott>,
+    // whether the link was bad on the step of its last transmission
+    channels: HashMap<(u32, u32), (bool, u32)>,
+}
+
+impl Default for ConnMap {
+    fn default() -> Self {
+        ConnMap {
+            prob: HashMap::new(),
+            delay: HashMap::new(),
+            bandwidth: HashMap::new(),
+            distributions: HashMap::new(),
+            default_distribution: DelayDistribution::default(),
+            min_delay: MIN_DELAY,
+            bursts: HashMap::new(),
+            default_bursts: None,
+            channels: HashMap::new(),
+        }
+    }
 }
 
 impl ConnMap {
@@ -37,6 +256,53 @@ impl ConnMap {
         self.update_delay(second, first, delay);
     }
 
+    pub fn update_bandwidth(&mut self, from: u32, to: u32, bandwidth: f32) {
+        *self.bandwidth.entry((from, to)).or_default() = bandwidth;
+    }
+
+    pub fn update_bandwidth_both(&mut self, first: u32, second: u32, bandwidth: f32) {
+        self.update_bandwidth(first, second, bandwidth);
+        self.update_bandwidth(second, first, bandwidth);
+    }
+
+    pub fn update_distribution(&mut self, from: u32, to: u32, distribution: DelayDistribution) {
+        self.distributions.insert((from, to), distribution);
+    }
+
+    pub fn update_distribution_both(
+        &mut self,
+        first: u32,
+        second: u32,
+        distribution: DelayDistribution,
+    ) {
+        self.update_distribution(first, second, distribution.clone());
+        self.update_distribution(second, first, distribution);
+    }
+
+    /// Distribution of the links without their own
+    pub fn set_default_distribution(&mut self, distribution: DelayDistribution) {
+        self.default_distribution = distribution;
+    }
+
+    /// Shortest delay of a message, at least a step
+    pub fn set_min_delay(&mut self, min_delay: u32) {
+        self.min_delay = min_delay.max(1);
+    }
+
+    pub fn update_bursts(&mut self, from: u32, to: u32, bursts: GilbertElliott) {
+        self.bursts.insert((from, to), bursts);
+    }
+
+    pub fn update_bursts_both(&mut self, first: u32, second: u32, bursts: GilbertElliott) {
+        self.update_bursts(first, second, bursts);
+        self.update_bursts(second, first, bursts);
+    }
+
+    /// Bursty loss of the links without their own
+    pub fn set_default_bursts(&mut self, bursts: Option<GilbertElliott>) {
+        self.default_bursts = bursts;
+    }
+
     pub fn update(&mut self, from: u32, to: u32, prob: f32, delay: i32) {
         self.update_prob(from, to, prob);
         self.update_delay(from, to, delay);
@@ -55,20 +321,222 @@ impl ConnMap {
         self.delay.get(&(from, to)).cloned().unwrap_or_default()
     }
 
-    pub fn test(&self, from: u32, to: u32, rng: &mut impl rand::Rng) -> bool {
-        rng.gen_bool(self.prob(from, to) as f64)
+    pub fn distribution(&self, from: u32, to: u32) -> &DelayDistribution {
+        self.distributions
+            .get(&(from, to))
+            .unwrap_or(&self.default_distribution)
+    }
+
+    pub fn bursts(&self, from: u32, to: u32) -> Option<GilbertElliott> {
+        self.bursts
+            .get(&(from, to))
+            .cloned()
+            .or(self.default_bursts)
+    }
+
+    /// Whether the link was bad the last time it was used
+    pub fn bad(&self, from: u32, to: u32) -> bool {
+        self.channels.get(&(from, to)).is_some_and(|&(bad, _)| bad)
+    }
+
+    pub fn min_delay(&self) -> u32 {
+        self.min_delay
+    }
+
+    pub fn bandwidth(&self, from: u32, to: u32) -> Option<f32> {
+        self.bandwidth.get(&(from, to)).cloned()
     }
 
-    pub fn get(&self, from: u32, to: u32, rng: &mut impl rand::Rng) -> Option<u32> {
-        if !self.test(from, to, rng) {
+    // steps it takes to put `size` bytes on the link
+    pub fn transmission_delay(&self, from: u32, to: u32, size: u32) -> u32 {
+        match self.bandwidth(from, to) {
+            Some(bandwidth) if bandwidth > 0.0 => (size as f32 / bandwidth).ceil() as u32,
+            _ => 0,
+        }
+    }
+
+    pub fn test(&mut self, from: u32, to: u32, step: u32, rng: &mut impl Rng) -> bool {
+        let prob = self.prob(from, to) as f64;
+        // no link, no burst state and no draw for it
+        if prob == 0. {
+            return false;
+        }
+        let Some(bursts) = self.bursts(from, to) else {
+            return rng.gen_bool(prob);
+        };
+        // the state is only looked at when the link is used, in between it
+        // changes the way the chances say
+        let bad = match self.channels.get(&(from, to)) {
+            Some(&(bad, at)) => rng.gen_bool(bursts.bad_after(bad, step.saturating_sub(at))),
+            None => rng.gen_bool(bursts.bad_share()),
+        };
+        self.channels.insert((from, to), (bad, step));
+        let loss = if bad {
+            bursts.bad_loss
+        } else {
+            bursts.good_loss
+        };
+        rng.gen_bool((prob * (1. - loss)).clamp(0., 1.))
+    }
+
+    pub fn get(
+        &mut self,
+        from: u32,
+        to: u32,
+        size: u32,
+        step: u32,
+        rng: &mut impl Rng,
+    ) -> Option<u32> {
+        if !self.test(from, to, step, rng) {
             return None;
         }
-        let rand = rand_distr::Normal::new(
-            (DEFAULT_DELAY + self.delay(from, to)) as f32,
-            DEVIATION as f32,
-        )
-        .unwrap();
-        // todo fix it
-        return Some(std::cmp::max(MIN_DELAY, rand.sample(rng) as i32) as u32);
+        // the shift moves the whole distribution, the delay is in whole steps
+        let sample = self.distribution(from, to).sample(rng) + self.delay(from, to) as f32;
+        let delay = (sample as i32).max(self.min_delay as i32) as u32;
+        Some(delay + self.transmission_delay(from, to, size))
     }
 }
+
+#[test]
+fn delays() {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    let _ = env_logger::builder().try_init();
+
+    let mut rng = StdRng::seed_from_u64(1);
+    let mut conn = ConnMap::default();
+    conn.update_both(0, 1, 1., 20);
+    let mut samples = |conn: &mut ConnMap, from, to| -> Vec<u32> {
+        (0..10000)
+            .map(|_| conn.get(from, to, 0, 0, &mut rng).unwrap())
+            .collect()
+    };
+    let mean = |samples: &[u32]| samples.iter().sum::<u32>() as f64 / samples.len() as f64;
+
+    // normal around 50, shifted by the link's delay
+    let delays = samples(&mut conn, 0, 1);
+    assert!((mean(&delays) - 69.5).abs() < 1., "{}", mean(&delays));
+    assert!(delays.iter().all(|&delay| delay >= conn.min_delay()));
+
+    // a link of its own, the other way keeps the default
+    conn.update_distribution(0, 1, DelayDistribution::Constant(5.));
+    assert!(samples(&mut conn, 0, 1).iter().all(|&delay| delay == 25));
+    assert!(samples(&mut conn, 1, 0).iter().any(|&delay| delay != 25));
+    // stretched to the minimum, which is at least a step
+    conn.update_delay(0, 1, -20);
+    assert!(samples(&mut conn, 0, 1).iter().all(|&delay| delay == 10));
+    conn.set_min_delay(0);
+    assert!(samples(&mut conn, 0, 1).iter().all(|&delay| delay == 1));
+    conn.update_delay(0, 1, 0);
+
+    conn.update_distribution(0, 1, DelayDistribution::Uniform { min: 30., max: 40. });
+    let delays = samples(&mut conn, 0, 1);
+    assert!(delays.iter().all(|&delay| (30..=40).contains(&delay)));
+    // rounded down to whole steps
+    assert!((mean(&delays) - 34.5).abs() < 0.5);
+
+    conn.set_default_distribution(DelayDistribution::Exponential { mean: 100. });
+    let delays = samples(&mut conn, 1, 0);
+    assert!((mean(&delays) - 120.).abs() < 5., "{}", mean(&delays));
+
+    // the median is e^mu, shifted
+    conn.update_distribution_both(0, 1, DelayDistribution::LogNormal { mu: 4., sigma: 0.5 });
+    let mut delays = samples(&mut conn, 1, 0);
+    delays.sort_unstable();
+    assert!((delays[delays.len() / 2] as f64 - (4f64.exp() + 20.)).abs() < 2.);
+    assert_eq!(conn.distribution(0, 1), conn.distribution(1, 0));
+
+    // measured delays: mostly quick, a fifth of them late
+    let path = std::env::temp_dir().join(format!("delays-{}.txt", std::process::id()));
+    std::fs::write(&path, "10 12 14 16 18 11 13 15\n 17 19 90 100").unwrap();
+    let histogram = Histogram::load(&path, 9).unwrap();
+    std::fs::remove_file(path).unwrap();
+    assert_eq!(histogram.weights, [10., 0., 0., 0., 0., 0., 0., 0., 2.]);
+    conn.update_distribution(0, 1, DelayDistribution::Histogram(histogram));
+    let delays = samples(&mut conn, 0, 1);
+    assert!(delays.iter().all(|&delay| (10..=100).contains(&delay)));
+    let late = delays.iter().filter(|&&delay| delay >= 90).count() as f64;
+    assert!((late / delays.len() as f64 - 1. / 6.).abs() < 0.02);
+    // and the time to put the bytes on the link on top
+    conn.update_bandwidth(0, 1, 2.);
+    for _ in 0..1000 {
+        assert!(conn.get(0, 1, 10, 0, &mut rng).unwrap() >= 15);
+    }
+
+    assert!(Histogram::new(vec![0., 1.], vec![1., 1.]).is_err());
+    assert!(Histogram::new(vec![0., 1., 0.5], vec![1., 1.]).is_err());
+    assert!(Histogram::new(vec![0., 1.], vec![0.]).is_err());
+    assert!(Histogram::from_samples(&[], 5).is_err());
+    assert_eq!(
+        Histogram::from_samples(&[7., 7.], 3)
+            .unwrap()
+            .sample(&mut rng),
+        7.
+    );
+}
+
+#[test]
+fn bursts() {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    let _ = env_logger::builder().try_init();
+
+    let mut rng = StdRng::seed_from_u64(1);
+    let mut conn = ConnMap::default();
+    conn.update_both(0, 1, 1., 0);
+    // bad a tenth of the time, in bursts of 10 steps
+    let bursts = GilbertElliott::new(90., 10.);
+    assert!((bursts.bad_share() - 0.1).abs() < 1e-9);
+    assert_eq!(bursts.bad_after(true, 0), 1.);
+    assert_eq!(bursts.bad_after(false, 0), 0.);
+    assert!((bursts.bad_after(false, 1) - bursts.to_bad).abs() < 1e-9);
+    assert!((bursts.bad_after(true, 1000) - 0.1).abs() < 1e-9);
+
+    conn.update_bursts(0, 1, bursts);
+    let (mut lost, mut lost_after_lost, mut lost_before) = (0, 0, false);
+    let steps = 100000;
+    for step in 0..steps {
+        let delivered = conn.test(0, 1, step, &mut rng);
+        assert_eq!(delivered, !conn.bad(0, 1));
+        if !delivered {
+            lost += 1;
+            if lost_before {
+                lost_after_lost += 1;
+            }
+        }
+        lost_before = !delivered;
+        // the other way loses nothing
+        assert!(conn.test(1, 0, step, &mut rng));
+    }
+    // as many losses as independent ones with 0.9, but together
+    let share = lost as f64 / steps as f64;
+    assert!((share - 0.1).abs() < 0.02, "{}", share);
+    let together = lost_after_lost as f64 / lost as f64;
+    assert!((together - 0.9).abs() < 0.02, "{}", together);
+
+    // used now and then the link forgets its state, but not its share
+    let mut lost = 0;
+    for step in 0..10000 {
+        if !conn.test(0, 1, steps + step * 1000, &mut rng) {
+            lost += 1;
+        }
+    }
+    assert!((lost as f64 / 10000. - 0.1).abs() < 0.02);
+
+    // for all the links, partly lossy in the good state
+    conn.set_default_bursts(Some(GilbertElliott {
+        good_loss: 0.5,
+        ..bursts
+    }));
+    let delivered = (0..10000)
+        .filter(|&step| conn.test(1, 0, step, &mut rng))
+        .count() as f64;
+    assert!((delivered / 10000. - 0.45).abs() < 0.03, "{}", delivered);
+    assert_eq!(conn.bursts(0, 1), Some(bursts));
+
+    // no link, no state and nothing drawn
+    let mut before = rng.clone();
+    assert!(!conn.test(0, 2, steps, &mut rng));
+    assert!(!conn.channels.contains_key(&(0, 2)));
+    assert_eq!(rng.gen::<u64>(), before.gen::<u64>());
+}
@@ -0,0 +1,145 @@
+#[derive(Debug, Clone, Copy)]
+pub struct EnergyParams {
+    // battery of every node
+    pub capacity: f64,
+    // per byte put on the air or received
+    pub transmit: f64,
+    pub receive: f64,
+    // per step with the radio on or asleep
+    pub idle: f64,
+    pub sleep: f64,
+}
+
+/// Batteries of the nodes. A node whose battery is empty is dead: it
+/// neither sends nor receives anything anymore.
+///
+/// Idle and sleep time is kept in steps rather than subtracted from the
+/// battery as it goes, so jumping over many steps at once runs out of battery
+/// at exactly the same step as going one by one.
+pub struct Energy {
+    params: EnergyParams,
+    // spent on transmitting and receiving
+    spent: Vec<f64>,
+    idle: Vec<u32>,
+    asleep: Vec<u32>,
+    died: Vec<Option<u32>>,
+    // idle and sleep time is accounted up to this step
+    updated: u32,
+}
+
+impl Energy {
+    pub fn new(params: EnergyParams, size: u32) -> Energy {
+        Energy {
+            params,
+            spent: vec![0.0; size as usize],
+            idle: vec![0; size as usize],
+            asleep: vec![0; size as usize],
+            died: vec![None; size as usize],
+            updated: 0,
+        }
+    }
+
+    pub fn params(&self) -> &EnergyParams {
+        &self.params
+    }
+
+    pub fn alive(&self, id: u32) -> bool {
+        self.died[id as usize].is_none()
+    }
+
+    pub fn consumed(&self, id: u32) -> f64 {
+        self.consumed_with(id, 0, 0)
+    }
+
+    fn consumed_with(&self, id: u32, idle: u32, asleep: u32) -> f64 {
+        let id = id as usize;
+        self.spent[id]
+            + (self.idle[id] + idle) as f64 * self.params.idle
+            + (self.asleep[id] + asleep) as f64 * self.params.sleep
+    }
+
+    fn die(&mut self, id: u32, step: u32) {
+        log::info!("node {} ran out of battery at {}", id, step);
+        self.died[id as usize] = Some(step);
+    }
+
+    // first of the next `steps` steps the battery runs out at, `sleeping`
+    // tells which rate is paid
+    fn lasts(&self, id: u32, steps: u32, sleeping: bool) -> Option<u32> {
+        let consumed = |k| match sleeping {
+            true => self.consumed_with(id, 0, k),
+            false => self.consumed_with(id, k, 0),
+        };
+        if steps == 0 || consumed(steps) < self.params.capacity {
+            return None;
+        }
+        // binary search, so the answer is the same however steps are grouped
+        let (mut lo, mut hi) = (1, steps);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if consumed(mid) >= self.params.capacity {
+                hi = mid;
+            } else {
+                lo = mid + 1;
+            }
+        }
+        Some(lo)
+    }
+
+    /// Pays for idling and sleeping until `step`, `asleep_until[id]` is the
+    /// step node `id` sleeps until and crashed nodes in `down` pay nothing.
+    /// Returns the nodes that died and when
+    pub fn advance(&mut self, step: u32, asleep_until: &[u32], down: &[bool]) -> Vec<(u32, u32)> {
+        let mut died = Vec::new();
+        if step <= self.updated {
+            return died;
+        }
+        let from = self.updated;
+        for id in 0..self.spent.len() as u32 {
+            if !self.alive(id) || down[id as usize] {
+                continue;
+            }
+            let wakes = asleep_until[id as usize].clamp(from, step);
+            let asleep = wakes - from;
+            if let Some(lasted) = self.lasts(id, asleep, true) {
+                self.asleep[id as usize] += lasted;
+                self.die(id, from + lasted);
+                died.push((id, from + lasted));
+                continue;
+            }
+            self.asleep[id as usize] += asleep;
+            let idle = step - wakes;
+            if let Some(lasted) = self.lasts(id, idle, false) {
+                self.idle[id as usize] += lasted;
+                self.die(id, wakes + lasted);
+                died.push((id, wakes + lasted));
+                continue;
+            }
+            self.idle[id as usize] += idle;
+        }
+        self.updated = step;
+        died
+    }
+
+    fn spend(&mut self, id: u32, cost: f64, step: u32) -> Option<u32> {
+        if !self.alive(id) {
+            return None;
+        }
+        self.spent[id as usize] += cost;
+        if self.consumed(id) < self.params.capacity {
+            return None;
+        }
+        self.die(id, step);
+        Some(step)
+    }
+
+    /// Pays for sending `size` bytes, returns the step the node died at if
+    /// that was the last of its battery
+    pub fn transmit(&mut self, id: u32, size: u32, step: u32) -> Option<u32> {
+        self.spend(id, size as f64 * self.params.transmit, step)
+    }
+
+    pub fn receive(&mut self, id: u32, size: u32, step: u32) -> Option<u32> {
+        self.spend(id, size as f64 * self.params.receive, step)
+    }
+}
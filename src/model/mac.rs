@@ -0,0 +1,113 @@
+use super::*;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+#[derive(Debug, Clone, Copy)]
+pub struct CsmaParams {
+    // initial contention window, in steps, doubled after every busy attempt
+    pub window: u32,
+    // after that many back-offs the node transmits no matter what
+    pub max_attempts: u32,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MacParams {
+    // if set, nodes hearing someone else postpone their transmissions
+    pub csma: Option<CsmaParams>,
+}
+
+/// Medium access: receptions that overlap in time at the same receiver
+/// collide and are lost, and senders may back off while the channel is busy.
+///
+/// A reception occupies the receiver for the steps it takes to transmit the
+/// message over the link, but at least one step, right before it arrives.
+pub struct Mac<T> {
+    params: MacParams,
+    // receiver -> (from, start, end) of every reception that is not over yet
+    receptions: HashMap<u32, Vec<(u32, u32, u32)>>,
+    // (from, to, end) of the receptions that collided
+    collided: HashSet<(u32, u32, u32)>,
+    // transmissions postponed by the back-off, with the number of attempts
+    deferred: BTreeMap<u32, Vec<(u32, MessageType<T>, u32)>>,
+}
+
+impl<T> Mac<T> {
+    pub fn new(params: MacParams) -> Mac<T> {
+        Mac {
+            params,
+            receptions: Default::default(),
+            collided: Default::default(),
+            deferred: Default::default(),
+        }
+    }
+
+    pub fn params(&self) -> &MacParams {
+        &self.params
+    }
+
+    /// Registers a reception at `to` during `[start, end)`, returns false if
+    /// it overlaps with another one
+    pub fn stage(&mut self, from: u32, to: u32, start: u32, end: u32) -> bool {
+        let receptions = self.receptions.entry(to).or_default();
+        let mut collided = false;
+        for &(other, other_start, other_end) in receptions.iter() {
+            if start < other_end && other_start < end {
+                self.collided.insert((other, to, other_end));
+                collided = true;
+            }
+        }
+        if collided {
+            self.collided.insert((from, to, end));
+        }
+        receptions.push((from, start, end));
+        !collided
+    }
+
+    /// Whether the message from `from` arriving at `to` at `step` survived
+    pub fn deliver(&self, from: u32, to: u32, step: u32) -> bool {
+        !self.collided.contains(&(from, to, step))
+    }
+
+    /// Whether `id` hears anybody at `step`
+    pub fn busy(&self, id: u32, step: u32) -> bool {
+        self.receptions
+            .get(&id)
+            .map(|receptions| {
+                receptions
+                    .iter()
+                    .any(|&(_, start, end)| start <= step && step < end)
+            })
+            .unwrap_or_default()
+    }
+
+    /// Back-off before the attempt number `attempts`, if the node still has to
+    /// wait at all
+    pub fn backoff(&self, attempts: u32, rng: &mut impl rand::Rng) -> Option<u32> {
+        let csma = self.params.csma?;
+        if attempts >= csma.max_attempts {
+            return None;
+        }
+        let window = csma.window.max(1).saturating_mul(1 << attempts.min(16));
+        Some(rng.gen_range(1..=window))
+    }
+
+    pub fn defer(&mut self, at: u32, from: u32, data: MessageType<T>, attempts: u32) {
+        self.deferred
+            .entry(at)
+            .or_default()
+            .push((from, data, attempts));
+    }
+
+    pub fn next_deferred(&self) -> Option<u32> {
+        self.deferred.keys().next().cloned()
+    }
+
+    /// Takes the transmissions postponed until `step` and forgets the
+    /// receptions that are over
+    pub fn take_deferred(&mut self, step: u32) -> Vec<(u32, MessageType<T>, u32)> {
+        for receptions in self.receptions.values_mut() {
+            receptions.retain(|&(_, _, end)| end >= step);
+        }
+        self.collided.retain(|&(_, _, end)| end >= step);
+        self.deferred.remove(&step).unwrap_or_default()
+    }
+}
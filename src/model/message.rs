@@ -8,13 +8,35 @@ pub struct RequestMessage {
 
 #[derive(Debug)]
 pub enum MessageType<T> {
+    /// The request has reached its destination
     Request(RequestMessage),
+    /// Broadcast to every neighbour
     Comm(T),
+    /// Sent to a single neighbour, nobody else hears it
+    Unicast(u32 /* to */, T),
 }
 
 #[derive(Debug)]
 pub struct Message<T> {
-    pub data: MessageType<T>,
+    pub data: T,
     pub from: u32,
     pub to: u32,
 }
+
+/// Size of a message on the air, in bytes
+pub trait WireSize {
+    fn wire_size(&self) -> u32;
+}
+
+/// Tells data traffic from control traffic (heartbeats, beacons, acks)
+pub trait Payload {
+    /// Id of the requested message this carries, if any
+    fn payload(&self) -> Option<u32>;
+}
+
+impl WireSize for RequestMessage {
+    fn wire_size(&self) -> u32 {
+        // from, to, start and id
+        4 + 4 + 4 + 4
+    }
+}
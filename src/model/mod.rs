@@ -1,9 +1,24 @@
+pub mod churn;
 pub mod connections;
+pub mod energy;
+pub mod mac;
 pub mod message;
+#[allow(clippy::module_inception)]
 pub mod model;
+pub mod propagation;
+pub mod protocol;
 pub mod stats;
+pub mod topology;
+pub mod trace;
 
+pub use churn::*;
 pub use connections::*;
+pub use energy::*;
+pub use mac::*;
 pub use message::*;
 pub use model::*;
+pub use propagation::*;
+pub use protocol::*;
 pub use stats::*;
+pub use topology::*;
+pub use trace::*;
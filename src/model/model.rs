@@ -1,77 +1,207 @@
 use super::*;
 use rand::{prelude::SliceRandom, RngCore};
 use rand_distr::{Distribution, Uniform};
-use std::{collections::HashMap, fmt::Debug};
-use tokio::sync::{mpsc, watch};
+use std::collections::{BTreeMap, HashMap};
 
-pub struct Model<T, R> {
+pub struct Model<P: Protocol, R> {
     size: u32,
     step: u32,
-    send: mpsc::UnboundedReceiver<(u32, MessageType<T>)>,
-    recv: Vec<mpsc::UnboundedSender<Message<T>>>,
-    buffer: HashMap<u32, Vec<Message<T>>>,
-    done: mpsc::Receiver<()>,
+    nodes: Vec<P>,
+    params: P::Params,
+    ctx: Context<P::Message>,
+    buffer: BTreeMap<u32, Vec<Message<P::Message>>>,
+    timers: BTreeMap<u32, Vec<u32>>,
+    mac: Option<Mac<P::Message>>,
+    energy: Option<Energy>,
+    asleep_until: Vec<u32>,
+    down: Vec<bool>,
+    // (message, node) -> hops the first copy of the message to reach the
+    // node went through
+    hops: HashMap<(u32, u32), u32>,
+    trace: Option<Trace>,
     pub rng: R,
     pub conn: ConnMap,
-    ticks: watch::Sender<u32>,
     messages: u32,
     pub stats: Stats,
 }
 
-fn vec_chan<T>(
-    size: usize,
-) -> (
-    Vec<mpsc::UnboundedSender<T>>,
-    Vec<mpsc::UnboundedReceiver<T>>,
-) {
-    let mut txs = Vec::with_capacity(size as usize);
-    let mut rxs = Vec::with_capacity(size as usize);
-    for _ in 0..size {
-        let (tx, rx) = mpsc::unbounded_channel();
-        txs.push(tx);
-        rxs.push(rx);
-    }
-    (txs, rxs)
-}
+impl<P: Protocol, R> Model<P, R> {
+    pub fn new(size: u32, rng: R) -> Model<P, R> {
+        Model::with_params(size, Default::default(), rng)
+    }
 
-impl<T, R> Model<T, R> {
-    pub fn new(size: u32, rng: R) -> (Model<T, R>, Vec<Context<T>>) {
-        let recv = vec_chan(size as usize);
-        let send = mpsc::unbounded_channel();
-        let ticks = watch::channel(0).0;
-        let mut contexts = Vec::with_capacity(size as usize);
-        let done = mpsc::channel(size as usize);
-        for (id, recv) in recv.1.into_iter().enumerate() {
-            contexts.push(Context {
-                send: send.0.clone(),
-                recv,
-                done: done.0.clone(),
-                tick: ticks.subscribe(),
-                step: 0,
-                id: id as u32,
-            });
+    pub fn with_params(size: u32, params: P::Params, rng: R) -> Model<P, R> {
+        let mut ctx = Context::new();
+        let mut nodes = Vec::with_capacity(size as usize);
+        for id in 0..size {
+            ctx.enter(id, 0);
+            nodes.push(P::new(id, &params, &mut ctx));
         }
-        let model = Model {
+        let mut model = Model {
             size,
             step: 0,
-            send: send.1,
-            recv: recv.0,
+            nodes,
+            params,
+            ctx,
             buffer: Default::default(),
-            done: done.1,
+            timers: Default::default(),
+            mac: None,
+            energy: None,
+            asleep_until: vec![0; size as usize],
+            down: vec![false; size as usize],
+            hops: Default::default(),
+            trace: None,
             conn: Default::default(),
             rng,
-            ticks,
-            stats: Default::default(),
+            stats: Stats::new(size),
             messages: 0,
         };
-        (model, contexts)
+        model.schedule_wakeups();
+        model
+    }
+
+    pub fn current_step(&self) -> u32 {
+        self.step
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Turns on collisions between overlapping receptions
+    pub fn set_mac(&mut self, params: MacParams) {
+        self.mac = Some(Mac::new(params));
+    }
+
+    /// Gives every node a battery
+    pub fn set_energy(&mut self, params: EnergyParams) {
+        self.energy = Some(Energy::new(params, self.size));
+        self.stats.energy = vec![0.0; self.size as usize];
+    }
+
+    /// Records everything that happens from now on to the trace
+    pub fn set_trace(&mut self, trace: Trace) {
+        self.trace = Some(trace);
+    }
+
+    pub fn take_trace(&mut self) -> Option<Trace> {
+        self.trace.take()
+    }
+
+    pub fn trace_mut(&mut self) -> Option<&mut Trace> {
+        self.trace.as_mut()
+    }
+
+    pub fn record(&mut self, event: TraceEvent) {
+        if let Some(trace) = &mut self.trace {
+            trace.record(event);
+        }
+    }
+
+    pub fn alive(&self, id: u32) -> bool {
+        !self.down[id as usize]
+            && self
+                .energy
+                .as_ref()
+                .map(|energy| energy.alive(id))
+                .unwrap_or(true)
+    }
+
+    /// Crashes the node: until restarted it doesn't send, receive or tick
+    pub fn kill_node(&mut self, id: u32) {
+        log::info!("node {} is down at {}", id, self.step);
+        self.down[id as usize] = true;
+        self.record(TraceEvent::Down {
+            step: self.step,
+            node: id,
+        });
+    }
+
+    /// Brings a crashed node back, either with the state it had or as a
+    /// freshly started one
+    pub fn restart_node(&mut self, id: u32, keep_state: bool) {
+        if !self.down[id as usize] {
+            return;
+        }
+        log::info!("node {} is up at {}", id, self.step);
+        self.down[id as usize] = false;
+        self.record(TraceEvent::Up {
+            step: self.step,
+            node: id,
+        });
+        self.ctx.enter(id, self.step);
+        if keep_state {
+            // the timers that came while it was down are gone, let it
+            // schedule new ones
+            self.ctx.wake_at(self.step + 1);
+        } else {
+            self.asleep_until[id as usize] = 0;
+            self.nodes[id as usize] = P::new(id, &self.params, &mut self.ctx);
+        }
+        self.schedule_wakeups();
+    }
+
+    fn asleep(&self, id: u32) -> bool {
+        self.asleep_until[id as usize] > self.step
+    }
+
+    fn on_deaths(&mut self, mut died: Vec<(u32, u32)>) {
+        died.sort_by_key(|(_, at)| *at);
+        for (id, at) in died {
+            self.stats.on_death(at, self.size);
+            self.record(TraceEvent::Down { step: at, node: id });
+        }
+    }
+
+    fn update_energy_stats(&mut self) {
+        if let Some(energy) = &self.energy {
+            for (id, consumed) in self.stats.energy.iter_mut().enumerate() {
+                *consumed = energy.consumed(id as u32);
+            }
+        }
+    }
+
+    /// The first step that has anything to process: messages to transmit,
+    /// a delivery or a timer
+    pub fn next_event(&self) -> Option<u32> {
+        if !self.ctx.is_idle() {
+            return Some(self.step + 1);
+        }
+        let delivery = self.buffer.keys().next().cloned();
+        let timer = self.timers.keys().next().cloned();
+        let deferred = self.mac.as_ref().and_then(|mac| mac.next_deferred());
+        [delivery, timer, deferred].into_iter().flatten().min()
+    }
+
+    fn schedule_wakeups(&mut self) {
+        for (id, at) in self.ctx.take_wakeups() {
+            self.timers.entry(at).or_default().push(id);
+        }
+        for (id, until) in self.ctx.take_sleeps() {
+            self.asleep_until[id as usize] = until;
+        }
     }
 }
 
-impl<T: Clone + Debug, R: RngCore> Model<T, R> {
-    fn send_message(&mut self, from: u32, to: u32, data: &T) {
-        if let Some(delay) = self.conn.get(from, to, &mut self.rng) {
+impl<P: Protocol, R: RngCore> Model<P, R> {
+    fn send_message(&mut self, from: u32, to: u32, data: &P::Message) {
+        let size = data.wire_size();
+        let delay = self.conn.get(from, to, size, self.step, &mut self.rng);
+        // pairs without a link at all would flood the trace on every broadcast
+        if self.conn.prob(from, to) > 0.0 {
+            self.record(TraceEvent::Link {
+                step: self.step,
+                from,
+                to,
+                at: delay.map(|delay| self.step + delay),
+            });
+        }
+        if let Some(delay) = delay {
             let at = self.step + delay;
+            if let Some(mac) = &mut self.mac {
+                let airtime = std::cmp::max(1, self.conn.transmission_delay(from, to, size));
+                mac.stage(from, to, at - airtime, at);
+            }
             log::debug!(
                 "staged message from {} to {} at {}: {:?}",
                 from,
@@ -80,14 +210,14 @@ impl<T: Clone + Debug, R: RngCore> Model<T, R> {
                 data
             );
             self.buffer.entry(at).or_default().push(Message {
-                data: MessageType::Comm(data.clone()),
+                data: data.clone(),
                 from,
                 to,
             });
         }
     }
 
-    fn broadcast(&mut self, from: u32, data: &T) {
+    fn broadcast(&mut self, from: u32, data: &P::Message) {
         for to in 0..self.size {
             self.send_message(from, to, data);
         }
@@ -97,7 +227,16 @@ impl<T: Clone + Debug, R: RngCore> Model<T, R> {
         if data.to != sent {
             anyhow::bail!("wrong destination id");
         }
-        if !self.stats.delivered(data.id, self.step - data.start) {
+        let hops = self.hops.get(&(data.id, sent)).cloned().unwrap_or_default();
+        let first = self.stats.delivered(data.id, self.step - data.start, hops);
+        self.record(TraceEvent::Deliver {
+            step: self.step,
+            node: sent,
+            id: data.id,
+            hops,
+            duplicate: !first,
+        });
+        if !first {
             log::info!("got duplicate message, id {}", data.id);
         } else {
             log::info!(
@@ -115,44 +254,199 @@ impl<T: Clone + Debug, R: RngCore> Model<T, R> {
         }
     }
 
-    pub async fn step(&mut self) {
-        for _ in 0..self.size {
-            self.done.recv().await.unwrap();
+    fn transmit(&mut self, id: u32, data: MessageType<P::Message>, attempts: u32) {
+        if let (Some(mac), MessageType::Comm(_) | MessageType::Unicast(..)) = (&mut self.mac, &data)
+        {
+            if mac.busy(id, self.step) {
+                if let Some(backoff) = mac.backoff(attempts, &mut self.rng) {
+                    self.stats.on_backoff();
+                    mac.defer(self.step + backoff, id, data, attempts + 1);
+                    return;
+                }
+            }
+        }
+        let data = match data {
+            MessageType::Request(data) => return self.process_message(id, data),
+            _ if !self.alive(id) => return,
+            MessageType::Comm(data) => {
+                self.record(TraceEvent::Transmit {
+                    step: self.step,
+                    node: id,
+                    to: None,
+                    size: data.wire_size(),
+                    message: data.payload(),
+                });
+                self.stats.on_broadcast(data.wire_size());
+                self.broadcast(id, &data);
+                data
+            }
+            MessageType::Unicast(to, data) => {
+                self.record(TraceEvent::Transmit {
+                    step: self.step,
+                    node: id,
+                    to: Some(to),
+                    size: data.wire_size(),
+                    message: data.payload(),
+                });
+                self.stats.on_unicast(data.wire_size());
+                if self.conn.prob(id, to) == 0.0 && self.conn.prob(to, id) > 0.0 {
+                    self.stats.on_one_way_unicast();
+                }
+                self.send_message(id, to, &data);
+                data
+            }
+        };
+        let size = data.wire_size();
+        self.stats.on_transmit(id, size, data.payload().is_some());
+        if let Some(at) = self
+            .energy
+            .as_mut()
+            .and_then(|energy| energy.transmit(id, size, self.step))
+        {
+            self.on_deaths(vec![(id, at)]);
         }
+    }
+
+    pub fn step(&mut self) {
         self.step += 1;
         log::info!("step {}", self.step);
-        while let Ok((id, data)) = self.send.try_recv() {
-            match data {
-                MessageType::Request(data) => self.process_message(id, data),
-                MessageType::Comm(data) => self.broadcast(id, &data),
-            }
+        if let Some(energy) = &mut self.energy {
+            let died = energy.advance(self.step, &self.asleep_until, &self.down);
+            self.on_deaths(died);
+        }
+        let deferred = match &mut self.mac {
+            Some(mac) => mac.take_deferred(self.step),
+            None => Vec::new(),
+        };
+        for (id, data, attempts) in deferred {
+            self.transmit(id, data, attempts);
+        }
+        // nodes are always run in id order, so everything sent during the
+        // previous step is already ordered by sender
+        for (id, data) in self.ctx.take_outbox() {
+            self.transmit(id, data, 0);
         }
         if let Some(mut messages) = self.buffer.remove(&self.step) {
             messages.shuffle(&mut self.rng);
             for m in messages {
-                self.stats.on_message();
+                let reception = if self
+                    .mac
+                    .as_ref()
+                    .is_some_and(|mac| !mac.deliver(m.from, m.to, self.step))
+                {
+                    Reception::Collision
+                } else if !self.alive(m.to) {
+                    Reception::Dropped
+                } else if self.asleep(m.to) {
+                    Reception::Asleep
+                } else {
+                    Reception::Received
+                };
+                self.record(TraceEvent::Receive {
+                    step: self.step,
+                    from: m.from,
+                    to: m.to,
+                    message: m.data.payload(),
+                    reception,
+                });
+                match reception {
+                    Reception::Collision => {
+                        log::debug!("collision at {} from {}: {:?}", m.to, m.from, m.data);
+                        self.stats.on_collision();
+                        continue;
+                    }
+                    Reception::Dropped => {
+                        self.stats.on_dropped();
+                        continue;
+                    }
+                    Reception::Asleep => continue,
+                    Reception::Received => {}
+                }
+                let size = m.data.wire_size();
+                self.stats.on_message(m.to, size);
+                if self.conn.prob(m.to, m.from) == 0.0 {
+                    self.stats.on_one_way();
+                }
+                if let Some(message) = m.data.payload() {
+                    let hops = self
+                        .hops
+                        .get(&(message, m.from))
+                        .cloned()
+                        .unwrap_or_default()
+                        + 1;
+                    self.hops.entry((message, m.to)).or_insert(hops);
+                }
+                if let Some(at) = self
+                    .energy
+                    .as_mut()
+                    .and_then(|energy| energy.receive(m.to, size, self.step))
+                {
+                    self.on_deaths(vec![(m.to, at)]);
+                }
                 log::debug!("sending message from {} to {}: {:?}", m.from, m.to, m.data);
-                self.recv[m.to as usize].send(m).unwrap();
+                self.ctx.enter(m.to, self.step);
+                self.nodes[m.to as usize].on_receive(&mut self.ctx, m);
+            }
+        }
+        if let Some(mut ids) = self.timers.remove(&self.step) {
+            ids.sort_unstable();
+            ids.dedup();
+            for id in ids {
+                if !self.alive(id) {
+                    continue;
+                }
+                self.ctx.enter(id, self.step);
+                self.nodes[id as usize].on_tick(&mut self.ctx);
             }
         }
-        self.ticks.send(self.step).unwrap();
+        self.schedule_wakeups();
+        self.update_energy_stats();
+    }
+
+    /// Jumps over the steps with nothing to do and processes the next event,
+    /// but doesn't go past `until`; gives the same results as stepping one by
+    /// one. Returns the step the model is at afterwards
+    pub fn advance(&mut self, until: u32) -> u32 {
+        if self.step >= until {
+            return self.step;
+        }
+        let next = self.next_event().map_or(until, |next| next.min(until));
+        self.step = next - 1;
+        self.step();
+        self.step
+    }
+
+    pub fn run_until(&mut self, until: u32) {
+        while self.step < until {
+            self.advance(until);
+        }
     }
 
     pub fn request_message(&mut self, from: u32, to: u32) {
         let id = self.messages;
         log::debug!("requested message id {} from {} to {}", id, from, to,);
-        self.recv[from as usize]
-            .send(Message {
-                data: MessageType::Request(RequestMessage {
+        self.ctx.enter(from, self.step);
+        self.record(TraceEvent::Request {
+            step: self.step,
+            id,
+            from,
+            to,
+        });
+        if !self.alive(from) {
+            log::info!("node {} is dead, request {} is lost", from, id);
+        } else {
+            self.hops.insert((id, from), 0);
+            self.nodes[from as usize].on_request(
+                &mut self.ctx,
+                RequestMessage {
                     from,
                     to,
                     start: self.step,
                     id,
-                }),
-                from,
-                to,
-            })
-            .unwrap();
+                },
+            );
+        }
+        self.schedule_wakeups();
         self.stats.requested(id);
         self.messages += 1
     }
@@ -168,65 +462,95 @@ impl<T: Clone + Debug, R: RngCore> Model<T, R> {
     }
 }
 
-pub struct Context<T> {
-    send: mpsc::UnboundedSender<(u32, MessageType<T>)>,
-    recv: mpsc::UnboundedReceiver<Message<T>>,
-    tick: watch::Receiver<u32>,
-    done: mpsc::Sender<()>,
-    step: u32,
-    id: u32,
+#[test]
+fn first_copy_hops() {
+    use crate::protocols::GossipNode;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    let _ = env_logger::builder().try_init();
+
+    // 0 reaches 1 quicker through 2 than straight, 1 passes it on to 3
+    // only after the straight copy got there too
+    let mut model = Model::<GossipNode, _>::new(4, StdRng::seed_from_u64(1));
+    model
+        .conn
+        .set_default_distribution(DelayDistribution::Constant(10.));
+    model.conn.update_both(0, 2, 1., 0);
+    model.conn.update_both(2, 1, 1., 0);
+    model.conn.update_both(0, 1, 1., 40);
+    model.run_until(200);
+    model.request_message(0, 3);
+    model.run_until(300);
+    assert!(!model.stats.all_delivered());
+    model.conn.update_both(1, 3, 1., 0);
+    model.run_until(1000);
+    assert!(model.stats.all_delivered());
+    // through 2 and 1, not the fewest hops straight through 1
+    let stat = model.stats.messages.values().next().unwrap();
+    assert_eq!(stat.hops, 3);
 }
 
-impl<T> Context<T> {
-    pub fn send(&self, data: MessageType<T>) {
-        let _ = self.send.send((self.id, data));
-    }
+// awake for 10 steps, then asleep for 90, and so on
+#[cfg(test)]
+struct Napper {
+    awake: bool,
+}
 
-    async fn read_one(&mut self) -> Result<Option<Message<T>>, ()> {
-        if let Some(message) = self.try_read() {
-            return Ok(Some(message));
-        }
-        self.next_step().await?;
-        return Ok(None);
-    }
+#[cfg(test)]
+impl Protocol for Napper {
+    type Message = crate::protocols::GossipMessage;
+    type Params = ();
+    const NAME: &'static str = "napper";
 
-    pub async fn read_for(&mut self, steps: u32) -> Result<Option<Message<T>>, ()> {
-        for _ in 0..steps {
-            if let Some(m) = self.read_one().await? {
-                return Ok(Some(m));
-            }
-        }
-        return Ok(None);
+    fn new(_id: u32, _params: &(), ctx: &mut Context<Self::Message>) -> Self {
+        ctx.wake_at(10);
+        Napper { awake: true }
     }
 
-    pub async fn read(&mut self) -> Result<Message<T>, ()> {
-        loop {
-            if let Some(m) = self.read_one().await? {
-                return Ok(m);
-            }
-        }
-    }
+    fn on_request(&mut self, _ctx: &mut Context<Self::Message>, _request: RequestMessage) {}
 
-    pub fn try_read(&mut self) -> Option<Message<T>> {
-        self.recv.try_recv().ok()
-    }
+    fn on_receive(&mut self, _ctx: &mut Context<Self::Message>, _message: Message<Self::Message>) {}
 
-    pub async fn sleep(&mut self, steps: u32) -> Result<u32, ()> {
-        let mut step = 0;
-        for _ in 0..steps {
-            step = self.next_step().await?;
+    fn on_tick(&mut self, ctx: &mut Context<Self::Message>) {
+        let step = ctx.current_step();
+        if self.awake {
+            ctx.sleep_until(step + 90);
+        } else {
+            ctx.wake_at(step + 10);
         }
-        return Ok(step);
+        self.awake = !self.awake;
     }
+}
 
-    pub async fn next_step(&mut self) -> Result<u32, ()> {
-        let _ = self.done.send(()).await;
-        self.tick.changed().await.map_err(|_| ())?;
-        self.step = *self.tick.borrow();
-        Ok(self.step)
-    }
+#[test]
+fn idle_sleep_down() {
+    use rand::{rngs::StdRng, SeedableRng};
 
-    pub fn current_step(&self) -> u32 {
-        return self.step;
-    }
+    let _ = env_logger::builder().try_init();
+
+    let mut model = Model::<Napper, _>::new(2, StdRng::seed_from_u64(1));
+    model.set_energy(EnergyParams {
+        capacity: 1e9,
+        transmit: 0.,
+        receive: 0.,
+        idle: 1.,
+        sleep: 0.1,
+    });
+    model.kill_node(1);
+    model.run_until(1000);
+    model.restart_node(1, true);
+    model.run_until(2000);
+    let energy = model.energy.as_ref().unwrap();
+    // a tenth of the time at the idle rate, the rest at the sleep rate
+    assert!(
+        (energy.consumed(0) - 2000. * 0.19).abs() < 5.,
+        "{}",
+        energy.consumed(0)
+    );
+    // nothing while it was down, then napping again
+    assert!(
+        (energy.consumed(1) - 1000. * 0.19).abs() < 5.,
+        "{}",
+        energy.consumed(1)
+    );
 }
@@ -0,0 +1,213 @@
+use std::f64::consts::{PI, SQRT_2};
+
+// meters per second
+const SPEED_OF_LIGHT: f64 = 299_792_458.;
+// a step is a millisecond
+const STEPS_PER_SECOND: f64 = 1000.;
+// links weaker than that are left out of the ConnMap
+const MIN_PROBABILITY: f64 = 0.001;
+// closer than that the formulas blow up, in meters
+const MIN_DISTANCE: f64 = 0.1;
+
+/// How the power of a signal falls with the distance
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PathLoss {
+    /// Friis free space, the power falls with the square of the distance
+    FreeSpace,
+    /// Free space up to the crossover distance, then the reflection from the
+    /// ground cancels the signal out with the fourth power of the distance.
+    /// Both antennas are `height` meters above the ground
+    TwoRay { height: f64 },
+    /// Free space up to `reference` meters, then the power falls with the
+    /// distance to the power of `exponent`
+    LogDistance { exponent: f64, reference: f64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Propagation {
+    pub path_loss: PathLoss,
+    // dBm, antenna gains included
+    pub transmit_power: f64,
+    // dBm, weaker signals aren't received
+    pub sensitivity: f64,
+    // Hz
+    pub frequency: f64,
+    // dB, deviation of the log-normal shadowing, 0 turns it off
+    pub shadowing: f64,
+}
+
+impl Propagation {
+    fn wavelength(&self) -> f64 {
+        SPEED_OF_LIGHT / self.frequency
+    }
+
+    fn free_space_loss(&self, distance: f64) -> f64 {
+        20. * (4. * PI * distance / self.wavelength()).log10()
+    }
+
+    /// Mean power of the signal `distance` meters away, in dBm
+    pub fn received_power(&self, distance: f64) -> f64 {
+        let distance = distance.max(MIN_DISTANCE);
+        let loss = match self.path_loss {
+            PathLoss::FreeSpace => self.free_space_loss(distance),
+            PathLoss::TwoRay { height } => {
+                let crossover = 4. * PI * height * height / self.wavelength();
+                if distance <= crossover {
+                    self.free_space_loss(distance)
+                } else {
+                    40. * distance.log10() - 20. * (height * height).log10()
+                }
+            }
+            PathLoss::LogDistance {
+                exponent,
+                reference,
+            } => {
+                if distance <= reference {
+                    self.free_space_loss(distance)
+                } else {
+                    self.free_space_loss(reference)
+                        + 10. * exponent * (distance / reference).log10()
+                }
+            }
+        };
+        self.transmit_power - loss
+    }
+
+    /// Chance the signal is above the sensitivity. Without shadowing it is
+    /// all or nothing, with it the links fade out around the range
+    pub fn probability(&self, distance: f64) -> f64 {
+        let margin = self.received_power(distance) - self.sensitivity;
+        if self.shadowing <= 0. {
+            return if margin >= 0. { 1. } else { 0. };
+        }
+        let probability = 1. - 0.5 * erfc(margin / (self.shadowing * SQRT_2));
+        if probability < MIN_PROBABILITY {
+            0.
+        } else {
+            probability
+        }
+    }
+
+    /// Steps the signal travels, only kilometers away it's a step or more
+    pub fn delay(&self, distance: f64) -> i32 {
+        (distance / SPEED_OF_LIGHT * STEPS_PER_SECOND).round() as i32
+    }
+
+    /// Probability and delay of a link `distance` meters long, for the `ConnMap`
+    pub fn link(&self, distance: f64) -> (f32, i32) {
+        (self.probability(distance) as f32, self.delay(distance))
+    }
+
+    /// Longest distance the mean signal is received at, the shadowing makes
+    /// links that long work half the time
+    pub fn range(&self) -> f64 {
+        // the received power only falls with the distance
+        let (mut near, mut far) = (MIN_DISTANCE, MIN_DISTANCE);
+        while self.received_power(far) >= self.sensitivity {
+            near = far;
+            far *= 2.;
+            if far > SPEED_OF_LIGHT {
+                return f64::INFINITY;
+            }
+        }
+        for _ in 0..100 {
+            let middle = (near + far) / 2.;
+            if self.received_power(middle) >= self.sensitivity {
+                near = middle;
+            } else {
+                far = middle;
+            }
+        }
+        near
+    }
+}
+
+// complementary error function, within 1.2e-7 (Numerical Recipes erfcc)
+fn erfc(x: f64) -> f64 {
+    let z = x.abs();
+    let t = 1. / (1. + 0.5 * z);
+    let y = t
+        * (-z * z - 1.26551223
+            + t * (1.00002368
+                + t * (0.37409196
+                    + t * (0.09678418
+                        + t * (-0.18628806
+                            + t * (0.27886807
+                                + t * (-1.13520398
+                                    + t * (1.48851587 + t * (-0.82215223 + t * 0.17087277)))))))))
+            .exp();
+    if x >= 0. {
+        y
+    } else {
+        2. - y
+    }
+}
+
+#[test]
+fn propagation() {
+    let _ = env_logger::builder().try_init();
+
+    let free_space = Propagation {
+        path_loss: PathLoss::FreeSpace,
+        transmit_power: 0.,
+        sensitivity: -70.,
+        frequency: 2.4e9,
+        shadowing: 0.,
+    };
+    // about 80 dB at 100 meters on 2.4 GHz, 6 more every time it doubles
+    assert!((free_space.received_power(100.) + 80.05).abs() < 0.01);
+    let doubled = free_space.received_power(200.) - free_space.received_power(100.);
+    assert!((doubled + 6.02).abs() < 0.01);
+    let range = free_space.range();
+    assert!((range - 31.4).abs() < 0.1, "{}", range);
+    assert_eq!(free_space.probability(range - 0.1), 1.);
+    assert_eq!(free_space.probability(range + 0.1), 0.);
+
+    // free space up to the crossover, then 12 dB every time it doubles
+    let two_ray = Propagation {
+        path_loss: PathLoss::TwoRay { height: 1.5 },
+        ..free_space
+    };
+    assert_eq!(
+        two_ray.received_power(100.),
+        free_space.received_power(100.)
+    );
+    let doubled = two_ray.received_power(2000.) - two_ray.received_power(1000.);
+    assert!((doubled + 12.04).abs() < 0.01);
+    assert!(two_ray.received_power(1000.) < free_space.received_power(1000.));
+
+    // exponent 2 is free space
+    let log_distance = |exponent| Propagation {
+        path_loss: PathLoss::LogDistance {
+            exponent,
+            reference: 1.,
+        },
+        ..free_space
+    };
+    assert!((log_distance(2.).received_power(50.) - free_space.received_power(50.)).abs() < 1e-9);
+    assert!(log_distance(3.).range() < range);
+
+    // shadowing: half the time right at the range, fading around it
+    let shadowed = Propagation {
+        shadowing: 4.,
+        ..log_distance(3.)
+    };
+    let range = shadowed.range();
+    assert!((shadowed.probability(range) - 0.5).abs() < 1e-6);
+    let mut last = 1.;
+    for distance in (1..100).map(|i| i as f64) {
+        let probability = shadowed.probability(distance);
+        assert!(probability <= last);
+        last = probability;
+    }
+    assert!(shadowed.probability(range * 0.6) > 0.9);
+    assert!(shadowed.probability(range * 1.6) < 0.1);
+    assert_eq!(shadowed.probability(range * 10.), 0.);
+
+    assert_eq!(free_space.delay(100.), 0);
+    assert_eq!(free_space.delay(3e6), 10);
+
+    assert!((erfc(0.) - 1.).abs() < 1e-7);
+    assert!((erfc(1.) - 0.157299).abs() < 1e-6);
+    assert!((erfc(-1.) - 1.842701).abs() < 1e-6);
+}
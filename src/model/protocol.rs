@@ -0,0 +1,102 @@
+use super::*;
+use serde::{de::DeserializeOwned, Serialize};
+use std::fmt::Debug;
+
+/// Per-node state of a routing protocol, driven by the model.
+///
+/// Every callback gets a `Context` to send messages and schedule the next
+/// `on_tick` with. Anything sent during a step is transmitted at the start of
+/// the next one.
+pub trait Protocol {
+    type Message: Clone + Debug + WireSize + Payload;
+
+    /// Timeouts, periods and such, shared by all the nodes
+    type Params: Clone + Debug + Default + Serialize + DeserializeOwned;
+
+    /// Short name of the protocol in the results, like "gossip"
+    const NAME: &'static str;
+
+    /// Creates the state of node `id`, called when the model is built and
+    /// when a crashed node restarts from scratch
+    fn new(id: u32, params: &Self::Params, ctx: &mut Context<Self::Message>) -> Self;
+
+    /// The node was asked to deliver `request` to `request.to`
+    fn on_request(&mut self, ctx: &mut Context<Self::Message>, request: RequestMessage);
+
+    /// A message from one of the neighbours arrived
+    fn on_receive(&mut self, ctx: &mut Context<Self::Message>, message: Message<Self::Message>);
+
+    /// A step previously requested with `Context::wake_at` has come
+    fn on_tick(&mut self, ctx: &mut Context<Self::Message>);
+}
+
+pub struct Context<T> {
+    id: u32,
+    step: u32,
+    outbox: Vec<(u32, MessageType<T>)>,
+    wakeups: Vec<(u32, u32)>,
+    sleeps: Vec<(u32, u32)>,
+}
+
+impl<T> Context<T> {
+    pub(super) fn new() -> Context<T> {
+        Context {
+            id: 0,
+            step: 0,
+            outbox: Vec::new(),
+            wakeups: Vec::new(),
+            sleeps: Vec::new(),
+        }
+    }
+
+    pub fn send(&mut self, data: MessageType<T>) {
+        self.outbox.push((self.id, data));
+    }
+
+    pub fn broadcast(&mut self, data: T) {
+        self.send(MessageType::Comm(data));
+    }
+
+    pub fn unicast(&mut self, to: u32, data: T) {
+        self.send(MessageType::Unicast(to, data));
+    }
+
+    /// Schedules `on_tick` of the current node at `step`, but not earlier than
+    /// the next step
+    pub fn wake_at(&mut self, step: u32) {
+        self.wakeups
+            .push((self.id, std::cmp::max(step, self.step + 1)));
+    }
+
+    /// Turns the radio of the current node off until `step`: it hears
+    /// nothing in the meantime and `on_tick` is called when it wakes up
+    pub fn sleep_until(&mut self, step: u32) {
+        self.sleeps.push((self.id, step));
+        self.wake_at(step);
+    }
+
+    pub fn current_step(&self) -> u32 {
+        self.step
+    }
+
+    pub(super) fn is_idle(&self) -> bool {
+        self.outbox.is_empty() && self.wakeups.is_empty() && self.sleeps.is_empty()
+    }
+
+    pub(super) fn enter(&mut self, id: u32, step: u32) {
+        self.id = id;
+        self.step = step;
+    }
+
+    pub(super) fn take_outbox(&mut self) -> Vec<(u32, MessageType<T>)> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    pub(super) fn take_wakeups(&mut self) -> Vec<(u32, u32)> {
+        std::mem::take(&mut self.wakeups)
+    }
+
+    pub(super) fn take_sleeps(&mut self) -> Vec<(u32, u32)> {
+        std::mem::take(&mut self.sleeps)
+    }
+}
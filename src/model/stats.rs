@@ -1,44 +1,150 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use rand_distr::num_traits::ToPrimitive;
+use serde::{Deserialize, Serialize};
 
-#[derive(Copy, Clone, Default, Debug)]
+#[derive(Copy, Clone, Default, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct MessageStat {
     pub delivered: bool,
     pub steps: u32,
+    // hops the first delivered copy went through
+    pub hops: u32,
+    // copies delivered after the first one
+    pub duplicates: u32,
 }
 
-#[derive(Clone, Default, Debug)]
+#[derive(Copy, Clone, Default, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrafficStat {
+    pub transmissions: u32,
+    pub bytes: u64,
+}
+
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Stats {
+    // messages received by the nodes
     pub total: u32,
+    // transmissions made by the nodes, by kind
+    pub broadcasts: u32,
+    pub unicasts: u32,
+    // bytes in the messages counted in `total`
+    pub bytes_received: u64,
+    // bytes in the transmissions
+    pub bytes_sent: u64,
+    // receptions lost because they overlapped with another one
+    pub collisions: u32,
+    // transmissions postponed because the channel was busy
+    pub backoffs: u32,
+    // receptions lost because the receiver was dead or down
+    pub dropped: u32,
+    // receptions over links that don't work the other way
+    pub one_way: u32,
+    // unicasts to a node that is heard but can't be reached, like the next
+    // hop of a route learned over a one-way link
+    pub one_way_unicasts: u32,
     pub delivered: u32,
-    pub messages: HashMap<u32, MessageStat>,
+    // deliveries of messages that were already delivered
+    pub duplicates: u32,
+    // transmissions carrying requested messages, and everything else
+    pub data: TrafficStat,
+    pub control: TrafficStat,
+    // transmissions and receptions of every node
+    pub transmitted: Vec<u32>,
+    pub received: Vec<u32>,
+    pub messages: BTreeMap<u32, MessageStat>,
+    // energy consumed by every node, empty without the energy model
+    pub energy: Vec<f64>,
+    // nodes that ran out of battery
+    pub dead: u32,
+    pub first_death: Option<u32>,
+    // step the last node ran out of battery at
+    pub lifetime: Option<u32>,
 }
 
 impl Stats {
+    pub fn new(size: u32) -> Stats {
+        Stats {
+            transmitted: vec![0; size as usize],
+            received: vec![0; size as usize],
+            ..Default::default()
+        }
+    }
+
     pub fn all_delivered(&self) -> bool {
-        return self.delivered == self.messages.len() as u32;
+        self.delivered == self.messages.len() as u32
     }
 
-    pub fn delivered(&mut self, id: u32, steps: u32) -> bool {
+    pub fn delivered(&mut self, id: u32, steps: u32, hops: u32) -> bool {
         let stat = self.messages.entry(id).or_default();
         if stat.delivered {
+            stat.duplicates += 1;
+            self.duplicates += 1;
             return false;
         }
         self.delivered += 1;
         stat.delivered = true;
         stat.steps = steps;
-        return true;
+        stat.hops = hops;
+        true
     }
 
     pub fn requested(&mut self, id: u32) {
         self.messages.entry(id).or_default();
     }
 
-    pub fn on_message(&mut self) {
+    pub fn on_message(&mut self, to: u32, size: u32) {
         self.total += 1;
+        self.received[to as usize] += 1;
+        self.bytes_received += size as u64;
+    }
+
+    pub fn on_broadcast(&mut self, size: u32) {
+        self.broadcasts += 1;
+        self.bytes_sent += size as u64;
+    }
+
+    pub fn on_unicast(&mut self, size: u32) {
+        self.unicasts += 1;
+        self.bytes_sent += size as u64;
+    }
+
+    pub fn on_transmit(&mut self, from: u32, size: u32, data: bool) {
+        self.transmitted[from as usize] += 1;
+        let traffic = match data {
+            true => &mut self.data,
+            false => &mut self.control,
+        };
+        traffic.transmissions += 1;
+        traffic.bytes += size as u64;
+    }
+
+    pub fn on_collision(&mut self) {
+        self.collisions += 1;
+    }
+
+    pub fn on_backoff(&mut self) {
+        self.backoffs += 1;
+    }
+
+    pub fn on_dropped(&mut self) {
+        self.dropped += 1;
+    }
+
+    pub fn on_one_way(&mut self) {
+        self.one_way += 1;
+    }
+
+    pub fn on_one_way_unicast(&mut self) {
+        self.one_way_unicasts += 1;
+    }
+
+    pub fn on_death(&mut self, step: u32, size: u32) {
+        self.dead += 1;
+        self.first_death.get_or_insert(step);
+        if self.dead == size {
+            self.lifetime = Some(step);
+        }
     }
 
-    pub fn avg_delivery_time(&self) -> f64 {
+    pub fn avg_delivery_time(&self) -> Option<f64> {
         let mut sum = 0.;
         let mut count = 0.;
         for (_, stat) in self.messages.iter() {
@@ -47,6 +153,43 @@ impl Stats {
                 sum += stat.steps.to_f64().unwrap();
             }
         }
-        return sum / count;
+        (count > 0.).then(|| sum / count)
+    }
+
+    /// Delivery time that `percent` percent of the delivered messages fit
+    /// in, e.g. 50, 90 or 99
+    pub fn latency_percentile(&self, percent: f64) -> Option<u32> {
+        let mut steps: Vec<u32> = self
+            .messages
+            .values()
+            .filter(|stat| stat.delivered)
+            .map(|stat| stat.steps)
+            .collect();
+        if steps.is_empty() {
+            return None;
+        }
+        steps.sort_unstable();
+        // nearest rank
+        let rank = (percent / 100. * steps.len() as f64).ceil() as usize;
+        Some(steps[rank.clamp(1, steps.len()) - 1])
+    }
+
+    /// Share of the requested messages that were delivered, nothing
+    /// requested means nothing was lost
+    pub fn delivery_ratio(&self) -> f64 {
+        match self.messages.len() {
+            0 => 1.,
+            requested => self.delivered as f64 / requested as f64,
+        }
+    }
+
+    pub fn avg_hops(&self) -> Option<f64> {
+        let hops: Vec<u32> = self
+            .messages
+            .values()
+            .filter(|stat| stat.delivered)
+            .map(|stat| stat.hops)
+            .collect();
+        (!hops.is_empty()).then(|| hops.iter().sum::<u32>() as f64 / hops.len() as f64)
     }
 }
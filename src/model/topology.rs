@@ -0,0 +1,302 @@
+use std::collections::{HashSet, VecDeque};
+use std::f64::consts::TAU;
+
+use rand::Rng;
+
+use crate::model::ConnMap;
+
+/// What every link of a generated graph gets in the `ConnMap`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Link {
+    // probability that message will be delivered, [0; 1]
+    pub probability: f32,
+    // shift in delay distribution
+    pub delay: i32,
+}
+
+impl Link {
+    pub fn new(probability: f32, delay: i32) -> Self {
+        Link { probability, delay }
+    }
+}
+
+/// Where the nodes are and which of them hear each other. Links go both ways
+/// and are listed once, the positions are only for showing the graph
+#[derive(Debug, Clone, Default)]
+pub struct Graph {
+    pub positions: Vec<(f64, f64)>,
+    pub links: Vec<(u32, u32)>,
+}
+
+impl Graph {
+    pub fn size(&self) -> u32 {
+        self.positions.len() as u32
+    }
+
+    /// Links every pair of the graph in `conn` the same way
+    pub fn fill(&self, conn: &mut ConnMap, link: Link) {
+        self.fill_with(conn, |_, _| link);
+    }
+
+    /// Links every pair of the graph in `conn` with its own probability and delay
+    pub fn fill_with(&self, conn: &mut ConnMap, mut link: impl FnMut(u32, u32) -> Link) {
+        for &(a, b) in self.links.iter() {
+            let Link { probability, delay } = link(a, b);
+            conn.update_both(a, b, probability, delay);
+        }
+    }
+
+    pub fn neighbours(&self) -> Vec<Vec<u32>> {
+        let mut neighbours = vec![Vec::new(); self.positions.len()];
+        for &(a, b) in self.links.iter() {
+            neighbours[a as usize].push(b);
+            neighbours[b as usize].push(a);
+        }
+        neighbours
+    }
+
+    /// Hops from `from` to every node, none for the unreachable ones
+    pub fn distances(&self, from: u32) -> Vec<Option<u32>> {
+        let neighbours = self.neighbours();
+        let mut distances = vec![None; self.positions.len()];
+        distances[from as usize] = Some(0);
+        let mut queue = VecDeque::from([from]);
+        while let Some(node) = queue.pop_front() {
+            let distance = distances[node as usize].unwrap();
+            for &next in neighbours[node as usize].iter() {
+                if distances[next as usize].is_none() {
+                    distances[next as usize] = Some(distance + 1);
+                    queue.push_back(next);
+                }
+            }
+        }
+        distances
+    }
+
+    pub fn connected(&self) -> bool {
+        self.positions.is_empty() || self.distances(0).iter().all(Option::is_some)
+    }
+
+    /// Longest of the shortest paths, none if the graph isn't connected
+    pub fn diameter(&self) -> Option<u32> {
+        let mut diameter = 0;
+        for from in 0..self.size() {
+            for distance in self.distances(from) {
+                diameter = diameter.max(distance?);
+            }
+        }
+        Some(diameter)
+    }
+
+    pub fn average_degree(&self) -> f64 {
+        2. * self.links.len() as f64 / self.positions.len() as f64
+    }
+}
+
+// `size` spots on a unit circle around (0, 0)
+fn circle(size: u32) -> Vec<(f64, f64)> {
+    (0..size)
+        .map(|i| {
+            let angle = TAU * i as f64 / size as f64;
+            (angle.cos(), angle.sin())
+        })
+        .collect()
+}
+
+/// Every node linked to every other one
+pub fn complete(size: u32) -> Graph {
+    Graph {
+        positions: circle(size),
+        links: (0..size)
+            .flat_map(|i| (i + 1..size).map(move |j| (i, j)))
+            .collect(),
+    }
+}
+
+/// Every node linked to the previous and the next one
+pub fn line(size: u32) -> Graph {
+    Graph {
+        positions: (0..size).map(|i| (i as f64, 0.)).collect(),
+        links: (1..size).map(|i| (i - 1, i)).collect(),
+    }
+}
+
+/// A line with the last node linked back to the first one
+pub fn ring(size: u32) -> Graph {
+    let mut links: Vec<(u32, u32)> = (1..size).map(|i| (i - 1, i)).collect();
+    if size > 2 {
+        links.push((0, size - 1));
+    }
+    Graph {
+        positions: circle(size),
+        links,
+    }
+}
+
+/// `width` by `height` nodes, row by row, linked to the ones next to them
+pub fn grid(width: u32, height: u32) -> Graph {
+    let mut graph = Graph::default();
+    for y in 0..height {
+        for x in 0..width {
+            let node = y * width + x;
+            graph.positions.push((x as f64, y as f64));
+            if x > 0 {
+                graph.links.push((node - 1, node));
+            }
+            if y > 0 {
+                graph.links.push((node - width, node));
+            }
+        }
+    }
+    graph
+}
+
+/// Node 0 in the middle, linked to all the others
+pub fn star(size: u32) -> Graph {
+    let mut positions = vec![(0., 0.)];
+    positions.extend(circle(size.saturating_sub(1)));
+    positions.truncate(size as usize);
+    Graph {
+        positions,
+        links: (1..size).map(|i| (0, i)).collect(),
+    }
+}
+
+/// Every node has `children` children, filled level by level from node 0
+pub fn tree(size: u32, children: u32) -> Graph {
+    let children = children.max(1);
+    let mut graph = Graph::default();
+    // first node of the current level, its width and depth
+    let (mut first, mut width, mut level) = (0, 1, 0);
+    for i in 0..size {
+        if i >= first + width {
+            first += width;
+            width *= children;
+            level += 1;
+        }
+        let place = (i - first) as f64 + 0.5;
+        graph.positions.push((place / width as f64, level as f64));
+        if i > 0 {
+            graph.links.push(((i - 1) / children, i));
+        }
+    }
+    graph
+}
+
+/// Every pair is linked with `probability`, independently of the others
+pub fn erdos_renyi(size: u32, probability: f64, rng: &mut impl Rng) -> Graph {
+    let mut graph = Graph {
+        positions: circle(size),
+        links: Vec::new(),
+    };
+    for i in 0..size {
+        for j in i + 1..size {
+            if rng.gen_bool(probability) {
+                graph.links.push((i, j));
+            }
+        }
+    }
+    graph
+}
+
+/// Starts with `links + 1` nodes linked to each other, every next node gets
+/// `links` links to the previous ones, picked proportionally to their degree
+pub fn barabasi_albert(size: u32, links: u32, rng: &mut impl Rng) -> Graph {
+    let links = links.max(1);
+    let mut graph = complete(size.min(links + 1));
+    graph.positions = circle(size);
+    // every node is there once per link it has
+    let mut ends: Vec<u32> = graph.links.iter().flat_map(|&(a, b)| [a, b]).collect();
+    for node in links + 1..size {
+        let mut targets = HashSet::new();
+        while targets.len() < links as usize {
+            targets.insert(ends[rng.gen_range(0..ends.len())]);
+        }
+        let mut targets: Vec<u32> = targets.into_iter().collect();
+        // the set's order isn't the same between runs
+        targets.sort_unstable();
+        for target in targets {
+            graph.links.push((target, node));
+            ends.extend([target, node]);
+        }
+    }
+    graph
+}
+
+/// Nodes at random spots of a unit square, linked to the ones closer than `radius`
+pub fn random_geometric(size: u32, radius: f64, rng: &mut impl Rng) -> Graph {
+    let mut graph = Graph {
+        positions: (0..size).map(|_| (rng.gen(), rng.gen())).collect(),
+        links: Vec::new(),
+    };
+    for i in 0..size {
+        for j in i + 1..size {
+            let (a, b) = (graph.positions[i as usize], graph.positions[j as usize]);
+            if (a.0 - b.0).hypot(a.1 - b.1) <= radius {
+                graph.links.push((i, j));
+            }
+        }
+    }
+    graph
+}
+
+#[test]
+fn generators() {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    let _ = env_logger::builder().try_init();
+
+    let graph = complete(6);
+    assert_eq!(graph.links.len(), 15);
+    assert_eq!(graph.diameter(), Some(1));
+
+    let graph = line(10);
+    assert_eq!(graph.links.len(), 9);
+    assert_eq!(graph.diameter(), Some(9));
+
+    let graph = ring(10);
+    assert_eq!(graph.links.len(), 10);
+    assert_eq!(graph.diameter(), Some(5));
+
+    let graph = grid(4, 3);
+    assert_eq!(graph.links.len(), 3 * 3 + 4 * 2);
+    assert_eq!(graph.diameter(), Some(5));
+
+    let graph = star(7);
+    assert_eq!(graph.links.len(), 6);
+    assert_eq!(graph.diameter(), Some(2));
+
+    // 1 + 2 + 4 + 8
+    let graph = tree(15, 2);
+    assert_eq!(graph.links.len(), 14);
+    assert_eq!(graph.diameter(), Some(6));
+
+    let mut rng = StdRng::seed_from_u64(1);
+    assert!(erdos_renyi(10, 0., &mut rng).links.is_empty());
+    assert_eq!(erdos_renyi(10, 1., &mut rng).links.len(), 45);
+    let graph = erdos_renyi(100, 0.1, &mut rng);
+    assert!((graph.average_degree() - 9.9).abs() < 1.5);
+
+    // 3 links inside the first 3 nodes, then 2 for every next one
+    let graph = barabasi_albert(50, 2, &mut rng);
+    assert_eq!(graph.links.len(), 3 + 47 * 2);
+    assert!(graph.connected());
+    let links: HashSet<(u32, u32)> = graph.links.iter().cloned().collect();
+    assert_eq!(links.len(), graph.links.len());
+
+    assert_eq!(random_geometric(10, 0., &mut rng).links.len(), 0);
+    assert_eq!(random_geometric(10, 2., &mut rng).links.len(), 45);
+    let graph = random_geometric(30, 0.3, &mut rng);
+    for &(a, b) in graph.links.iter() {
+        let (a, b) = (graph.positions[a as usize], graph.positions[b as usize]);
+        assert!((a.0 - b.0).hypot(a.1 - b.1) <= 0.3);
+    }
+
+    let mut conn = ConnMap::default();
+    line(3).fill_with(&mut conn, |a, b| Link::new(0.5, (a + b) as i32));
+    assert_eq!(conn.prob(0, 1), 0.5);
+    assert_eq!(conn.prob(2, 1), 0.5);
+    assert_eq!(conn.prob(0, 2), 0.);
+    assert_eq!(conn.delay(1, 0), 1);
+    assert_eq!(conn.delay(1, 2), 3);
+}
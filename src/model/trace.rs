@@ -0,0 +1,371 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+/// What happened to a message that came out of a link
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reception {
+    Received,
+    /// Overlapped with another reception
+    Collision,
+    /// The receiver was dead or down
+    Dropped,
+    /// The receiver was asleep
+    Asleep,
+}
+
+/// A line of a trace. Every line starts with a letter for the kind of the
+/// event and the step it happened at, the rest are space separated fields,
+/// `-` standing for nothing:
+///
+/// ```text
+/// q <step> <id> <from> <to>                    message requested
+/// t <step> <node> <to|*> <size> <message|->    transmission, * is a broadcast
+/// l <step> <from> <to> <at|->                  link outcome, - is a loss
+/// r <step> <from> <to> <message|-> <+|c|x|z>   reception, see `Reception`
+/// d <step> <node> <id> <hops> <+|=>            delivery, = is a duplicate
+/// k <step> <node>                              node down
+/// u <step> <node>                              node up
+/// m <step> <node> <x> <y> <dx> <dy> <speed>      node heads from x y to dx dy
+/// ```
+///
+/// `message` is the id of the requested message a transmission carries, `-`
+/// for control traffic. A node keeps going at `speed` per step until it
+/// arrives or the next `m` line of it, nodes without any stay unplaced.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceEvent {
+    Request {
+        step: u32,
+        id: u32,
+        from: u32,
+        to: u32,
+    },
+    Transmit {
+        step: u32,
+        node: u32,
+        // none for a broadcast
+        to: Option<u32>,
+        size: u32,
+        message: Option<u32>,
+    },
+    /// The link between the nodes was tried, the message arrives at `at`
+    /// unless it was lost
+    Link {
+        step: u32,
+        from: u32,
+        to: u32,
+        at: Option<u32>,
+    },
+    Receive {
+        step: u32,
+        from: u32,
+        to: u32,
+        message: Option<u32>,
+        reception: Reception,
+    },
+    Deliver {
+        step: u32,
+        node: u32,
+        id: u32,
+        hops: u32,
+        duplicate: bool,
+    },
+    Down {
+        step: u32,
+        node: u32,
+    },
+    Up {
+        step: u32,
+        node: u32,
+    },
+    Move {
+        step: u32,
+        node: u32,
+        position: (f64, f64),
+        destination: (f64, f64),
+        speed: f64,
+    },
+}
+
+impl TraceEvent {
+    pub fn step(&self) -> u32 {
+        match *self {
+            TraceEvent::Request { step, .. }
+            | TraceEvent::Transmit { step, .. }
+            | TraceEvent::Link { step, .. }
+            | TraceEvent::Receive { step, .. }
+            | TraceEvent::Deliver { step, .. }
+            | TraceEvent::Down { step, .. }
+            | TraceEvent::Up { step, .. }
+            | TraceEvent::Move { step, .. } => step,
+        }
+    }
+}
+
+struct Optional(Option<u32>);
+
+impl fmt::Display for Optional {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(value) => write!(f, "{}", value),
+            None => write!(f, "-"),
+        }
+    }
+}
+
+impl fmt::Display for TraceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            TraceEvent::Request { step, id, from, to } => {
+                write!(f, "q {} {} {} {}", step, id, from, to)
+            }
+            TraceEvent::Transmit {
+                step,
+                node,
+                to,
+                size,
+                message,
+            } => {
+                let to = to.map_or("*".to_string(), |to| to.to_string());
+                write!(
+                    f,
+                    "t {} {} {} {} {}",
+                    step,
+                    node,
+                    to,
+                    size,
+                    Optional(message)
+                )
+            }
+            TraceEvent::Link { step, from, to, at } => {
+                write!(f, "l {} {} {} {}", step, from, to, Optional(at))
+            }
+            TraceEvent::Receive {
+                step,
+                from,
+                to,
+                message,
+                reception,
+            } => {
+                let reception = match reception {
+                    Reception::Received => '+',
+                    Reception::Collision => 'c',
+                    Reception::Dropped => 'x',
+                    Reception::Asleep => 'z',
+                };
+                write!(
+                    f,
+                    "r {} {} {} {} {}",
+                    step,
+                    from,
+                    to,
+                    Optional(message),
+                    reception
+                )
+            }
+            TraceEvent::Deliver {
+                step,
+                node,
+                id,
+                hops,
+                duplicate,
+            } => {
+                let duplicate = if duplicate { '=' } else { '+' };
+                write!(f, "d {} {} {} {} {}", step, node, id, hops, duplicate)
+            }
+            TraceEvent::Down { step, node } => write!(f, "k {} {}", step, node),
+            TraceEvent::Up { step, node } => write!(f, "u {} {}", step, node),
+            TraceEvent::Move {
+                step,
+                node,
+                position,
+                destination,
+                speed,
+            } => write!(
+                f,
+                "m {} {} {} {} {} {} {}",
+                step, node, position.0, position.1, destination.0, destination.1, speed
+            ),
+        }
+    }
+}
+
+impl FromStr for TraceEvent {
+    type Err = anyhow::Error;
+
+    fn from_str(line: &str) -> Result<TraceEvent, anyhow::Error> {
+        let mut fields = line.split_whitespace();
+        let mut next = || {
+            fields
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("too few fields in \"{}\"", line))
+        };
+        let number = |field: &str| -> Result<u32, anyhow::Error> {
+            field
+                .parse()
+                .map_err(|_| anyhow::anyhow!("bad number {} in \"{}\"", field, line))
+        };
+        let real = |field: &str| -> Result<f64, anyhow::Error> {
+            field
+                .parse()
+                .map_err(|_| anyhow::anyhow!("bad number {} in \"{}\"", field, line))
+        };
+        let optional = |field: &str| match field {
+            "-" => Ok(None),
+            field => number(field).map(Some),
+        };
+        let kind = next()?;
+        let step = number(next()?)?;
+        let event = match kind {
+            "q" => TraceEvent::Request {
+                step,
+                id: number(next()?)?,
+                from: number(next()?)?,
+                to: number(next()?)?,
+            },
+            "t" => TraceEvent::Transmit {
+                step,
+                node: number(next()?)?,
+                to: match next()? {
+                    "*" => None,
+                    to => Some(number(to)?),
+                },
+                size: number(next()?)?,
+                message: optional(next()?)?,
+            },
+            "l" => TraceEvent::Link {
+                step,
+                from: number(next()?)?,
+                to: number(next()?)?,
+                at: optional(next()?)?,
+            },
+            "r" => TraceEvent::Receive {
+                step,
+                from: number(next()?)?,
+                to: number(next()?)?,
+                message: optional(next()?)?,
+                reception: match next()? {
+                    "+" => Reception::Received,
+                    "c" => Reception::Collision,
+                    "x" => Reception::Dropped,
+                    "z" => Reception::Asleep,
+                    other => anyhow::bail!("unknown reception {} in \"{}\"", other, line),
+                },
+            },
+            "d" => TraceEvent::Deliver {
+                step,
+                node: number(next()?)?,
+                id: number(next()?)?,
+                hops: number(next()?)?,
+                duplicate: match next()? {
+                    "+" => false,
+                    "=" => true,
+                    other => anyhow::bail!("unknown delivery {} in \"{}\"", other, line),
+                },
+            },
+            "k" => TraceEvent::Down {
+                step,
+                node: number(next()?)?,
+            },
+            "u" => TraceEvent::Up {
+                step,
+                node: number(next()?)?,
+            },
+            "m" => TraceEvent::Move {
+                step,
+                node: number(next()?)?,
+                position: (real(next()?)?, real(next()?)?),
+                destination: (real(next()?)?, real(next()?)?),
+                speed: real(next()?)?,
+            },
+            other => anyhow::bail!("unknown event {} in \"{}\"", other, line),
+        };
+        Ok(event)
+    }
+}
+
+enum Sink {
+    Writer(Box<dyn Write>),
+    Memory(Vec<TraceEvent>),
+}
+
+/// Writes the events of a run, a line each, or keeps them for whoever is
+/// watching the run
+pub struct Trace {
+    sink: Sink,
+    // the first write that failed, the run goes on without the trace
+    error: Option<std::io::Error>,
+}
+
+impl Trace {
+    pub fn new(writer: impl Write + 'static) -> Trace {
+        Trace {
+            sink: Sink::Writer(Box::new(writer)),
+            error: None,
+        }
+    }
+
+    /// Keeps the events until they are taken with `drain`
+    pub fn memory() -> Trace {
+        Trace {
+            sink: Sink::Memory(Vec::new()),
+            error: None,
+        }
+    }
+
+    pub fn create(path: impl AsRef<Path>) -> Result<Trace, anyhow::Error> {
+        let path = path.as_ref();
+        let file = File::create(path)
+            .map_err(|err| anyhow::anyhow!("can't create {}: {}", path.display(), err))?;
+        Ok(Trace::new(BufWriter::new(file)))
+    }
+
+    pub fn record(&mut self, event: TraceEvent) {
+        match &mut self.sink {
+            Sink::Writer(writer) if self.error.is_none() => {
+                if let Err(err) = writeln!(writer, "{}", event) {
+                    log::error!("can't write the trace: {}", err);
+                    self.error = Some(err);
+                }
+            }
+            Sink::Writer(_) => {}
+            Sink::Memory(events) => events.push(event),
+        }
+    }
+
+    /// Events kept since the last call, none if they are written out
+    pub fn drain(&mut self) -> Vec<TraceEvent> {
+        match &mut self.sink {
+            Sink::Writer(_) => Vec::new(),
+            Sink::Memory(events) => std::mem::take(events),
+        }
+    }
+
+    /// Flushes the trace, fails if any of the events couldn't be written
+    pub fn finish(mut self) -> Result<(), anyhow::Error> {
+        if let Some(err) = self.error {
+            return Err(err.into());
+        }
+        if let Sink::Writer(writer) = &mut self.sink {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads back the events of a trace, empty lines and lines starting with `#`
+/// are skipped
+pub fn read_trace(reader: impl BufRead) -> Result<Vec<TraceEvent>, anyhow::Error> {
+    let mut events = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        events.push(line.parse()?);
+    }
+    Ok(events)
+}
@@ -1,108 +1,140 @@
 use crate::model::*;
-use std::collections::{HashMap, HashSet};
-use std::detect::__is_feature_detected::sha;
-use crate::protocols::cbr::RoutableMessage::Ack;
-
+use crate::protocols::common::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
 
 const CBR_BEACON_PERIOD: u32 = 100;
 const CBR_RETRY_PERIOD: u32 = 5 * 1000; /* 1 second */
-const CBR_DROP_TIMEOUT: u32 = 1 * 1000;
+const CBR_DROP_TIMEOUT: u32 = 1000;
 
 type HintTable = HashMap<u32, u32>;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum RoutableMessage {
-    Request(RequestMessage),
-    Ack(u32),
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CBRParams {
+    pub beacon_period: u32,
+    // steps before a request that wasn't acked is sent again
+    pub retry_period: u32,
+    // requests older than that are not relayed anymore
+    pub drop_timeout: u32,
 }
 
+impl Default for CBRParams {
+    fn default() -> Self {
+        CBRParams {
+            beacon_period: CBR_BEACON_PERIOD,
+            retry_period: CBR_RETRY_PERIOD,
+            drop_timeout: CBR_DROP_TIMEOUT,
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub enum CBRMessage {
     Beacon(u32 /* from */),
-    RoutingRequest((RoutableMessage, u32 /* destination */, u32 /* min_hint */, u32 /* origin_time */)),
+    RoutingRequest(
+        (
+            RoutableMessage,
+            u32, /* destination */
+            u32, /* min_hint */
+            u32, /* origin_time */
+        ),
+    ),
 }
 
+impl Payload for CBRMessage {
+    fn payload(&self) -> Option<u32> {
+        match self {
+            CBRMessage::Beacon(_) => None,
+            CBRMessage::RoutingRequest((rm, _, _, _)) => rm.payload(),
+        }
+    }
+}
 
-pub async fn cbr_actor(my_id: u32, mut ctx: Context<CBRMessage>) {
-    let mut last_transmission = ctx.current_step();
-    let mut table = HintTable::from([
-        (my_id, last_transmission),
-    ]);
-    ctx.send(MessageType::Comm(CBRMessage::Beacon(my_id)));
+impl WireSize for CBRMessage {
+    fn wire_size(&self) -> u32 {
+        1 + match self {
+            CBRMessage::Beacon(_) => 4,
+            CBRMessage::RoutingRequest((rm, _, _, _)) => rm.wire_size() + 3 * 4,
+        }
+    }
+}
 
-    log::info!("worker {} started", my_id);
+pub struct CBRNode {
+    id: u32,
+    params: CBRParams,
+    last_transmission: u32,
+    table: HintTable,
+    messages_to_send: Vec<(
+        RoutableMessage,
+        u32, /* destination */
+        u32, /* origin_time */
+    )>,
+    retries: BTreeMap<u32, (RequestMessage, i32 /* last_sent */)>,
+    // requests already relayed, every copy of them is ignored until they are
+    // too old to be relayed anyway
+    relayed: HashSet<(
+        i32, /* key */
+        u32, /* destination */
+        u32, /* origin_time */
+    )>,
+}
 
-    let mut messages_to_send = Vec::<(RoutableMessage, u32 /* destination */)>::new();
-    let mut retries = HashMap::<u32, (RequestMessage, i32 /* last_sent */)>::new();
-    while let Ok(event) = ctx.read_for(10).await {
-        match event {
-            Some(m) => match m.data {
-                MessageType::Request(m) => {
-                    retries.insert(m.id, (m.clone(), -(CBR_RETRY_PERIOD as i32)));
-                }
-                MessageType::Comm(m) => match m {
-                    CBRMessage::RoutingRequest((rm, destination, min_hint, origin_time)) => {
-                        if destination == my_id {
-                            // This message has achieved its addressee
-                            match rm {
-                                RoutableMessage::Request(rm) => {
-                                    ctx.send(MessageType::Request(rm.clone()));
-                                    messages_to_send.push((RoutableMessage::Ack(rm.id), rm.from));
-                                }
-                                RoutableMessage::Ack(message_id) => {
-                                    retries.remove(&message_id);
-                                }
-                            }
-                        } else {
-                            if ctx.current_step() - origin_time <= CBR_DROP_TIMEOUT {
-                                // Consider broadcasting it
-                                let mut shall_retransmit = !table.contains_key(&destination);
-                                if !shall_retransmit {
-                                    shall_retransmit |= (ctx.current_step() - table.get(&destination).unwrap()) <= min_hint
-                                }
-                                if shall_retransmit {
-                                    messages_to_send.push((rm.clone(), destination));
-                                }
-                            }
-                        }
-                    }
-                    CBRMessage::Beacon(from) => {
-                        table.insert(from, ctx.current_step());
-                    }
-                },
-            },
-            None => {}
-        }
-        for (_, (rm, last_sent)) in retries.iter_mut() {
-            if (ctx.current_step() as i32) - *last_sent >= CBR_RETRY_PERIOD as i32 {
-                messages_to_send.push((RoutableMessage::Request(rm.clone()), rm.to));
+// requests and acks of the same message apart
+fn key(rm: &RoutableMessage) -> i32 {
+    match rm {
+        RoutableMessage::Request(rm) => rm.id as i32,
+        RoutableMessage::Ack(id) => -(*id as i32) - 1,
+    }
+}
+
+impl CBRNode {
+    // sends everything that is due at the current step and schedules the next
+    // beacon or retry
+    fn flush(&mut self, ctx: &mut Context<CBRMessage>) {
+        let messages_to_send = &mut self.messages_to_send;
+        let table = &self.table;
+        for (_, (rm, last_sent)) in self.retries.iter_mut() {
+            if (ctx.current_step() as i32) - *last_sent >= self.params.retry_period as i32 {
+                messages_to_send.push((RoutableMessage::Request(*rm), rm.to, ctx.current_step()));
             }
         }
-        log::info!("Size of mq: {} (agent {}, retires: {}) [{:?}] \n RT:{:?}", messages_to_send.len(), my_id, retries.len(), messages_to_send, table);
+        log::info!(
+            "Size of mq: {} (agent {}, retires: {}) [{:?}] \n RT:{:?}",
+            messages_to_send.len(),
+            self.id,
+            self.retries.len(),
+            messages_to_send,
+            table
+        );
 
         // Deduplicate messages
-        messages_to_send.sort_by_key(|(rm, _)| match rm {
-            RoutableMessage::Request(rm) => rm.id as i32,
-            RoutableMessage::Ack(id) => -(*id as i32    ) - 1
-        });
+        messages_to_send
+            .sort_by_key(|(rm, destination, origin_time)| (key(rm), *destination, *origin_time));
         messages_to_send.dedup();
+        let drop_timeout = self.params.drop_timeout;
+        self.relayed
+            .retain(|(_, _, origin_time)| ctx.current_step() - origin_time <= drop_timeout);
         // Send all enqueued on this step messages
-        for (msg, destination) in messages_to_send.iter() {
+        for (msg, destination, origin_time) in messages_to_send.iter() {
             ctx.send(MessageType::Comm(CBRMessage::RoutingRequest((
-                msg.clone(),
+                *msg,
                 *destination,
                 if table.contains_key(destination) {
                     ctx.current_step() - table.get(destination).unwrap()
                 } else {
                     u32::MAX
                 },
-                ctx.current_step()
+                *origin_time,
             ))));
             match msg {
                 RoutableMessage::Request(rm) => {
-                    if retries.contains_key(&rm.id) {
-                        retries.entry(rm.id).and_modify(|(_, last_transmission)| { *last_transmission = ctx.current_step() as i32; });
+                    if self.retries.contains_key(&rm.id) {
+                        self.retries
+                            .entry(rm.id)
+                            .and_modify(|(_, last_transmission)| {
+                                *last_transmission = ctx.current_step() as i32;
+                            });
                     }
                 }
                 RoutableMessage::Ack(_) => {}
@@ -111,12 +143,89 @@ pub async fn cbr_actor(my_id: u32, mut ctx: Context<CBRMessage>) {
         log::info!("Mq: {}", messages_to_send.len());
         messages_to_send.clear();
 
-        if ctx.current_step() - last_transmission >= CBR_BEACON_PERIOD {
-            last_transmission = ctx.current_step();
-            ctx.send(MessageType::Comm(CBRMessage::Beacon(my_id)));
+        if ctx.current_step() - self.last_transmission >= self.params.beacon_period {
+            self.last_transmission = ctx.current_step();
+            ctx.send(MessageType::Comm(CBRMessage::Beacon(self.id)));
+        }
+
+        let mut next = self.last_transmission + self.params.beacon_period;
+        for (_, last_sent) in self.retries.values() {
+            next = next.min((*last_sent + self.params.retry_period as i32) as u32);
         }
+        ctx.wake_at(next);
     }
+}
+
+impl Protocol for CBRNode {
+    type Message = CBRMessage;
+    type Params = CBRParams;
+    const NAME: &'static str = "cbr";
 
+    fn new(id: u32, params: &CBRParams, ctx: &mut Context<CBRMessage>) -> Self {
+        let last_transmission = ctx.current_step();
+        ctx.send(MessageType::Comm(CBRMessage::Beacon(id)));
+        ctx.wake_at(last_transmission + params.beacon_period);
 
-    log::info!("worker {} stopped", my_id);
+        log::info!("worker {} started", id);
+
+        CBRNode {
+            id,
+            params: *params,
+            last_transmission,
+            table: HintTable::from([(id, last_transmission)]),
+            messages_to_send: Default::default(),
+            retries: Default::default(),
+            relayed: Default::default(),
+        }
+    }
+
+    fn on_request(&mut self, ctx: &mut Context<CBRMessage>, m: RequestMessage) {
+        self.retries
+            .insert(m.id, (m, -(self.params.retry_period as i32)));
+        self.flush(ctx);
+    }
+
+    fn on_receive(&mut self, ctx: &mut Context<CBRMessage>, m: Message<CBRMessage>) {
+        match m.data {
+            CBRMessage::RoutingRequest((rm, destination, min_hint, origin_time)) => {
+                if destination == self.id {
+                    // This message has achieved its addressee
+                    match rm {
+                        RoutableMessage::Request(rm) => {
+                            ctx.send(MessageType::Request(rm));
+                            self.messages_to_send.push((
+                                RoutableMessage::Ack(rm.id),
+                                rm.from,
+                                ctx.current_step(),
+                            ));
+                        }
+                        RoutableMessage::Ack(message_id) => {
+                            self.retries.remove(&message_id);
+                        }
+                    }
+                } else if ctx.current_step() - origin_time <= self.params.drop_timeout
+                    && !self.relayed.contains(&(key(&rm), destination, origin_time))
+                {
+                    // Consider broadcasting it
+                    let mut shall_retransmit = !self.table.contains_key(&destination);
+                    if !shall_retransmit {
+                        shall_retransmit |=
+                            (ctx.current_step() - self.table.get(&destination).unwrap()) <= min_hint
+                    }
+                    if shall_retransmit {
+                        self.relayed.insert((key(&rm), destination, origin_time));
+                        self.messages_to_send.push((rm, destination, origin_time));
+                    }
+                }
+            }
+            CBRMessage::Beacon(from) => {
+                self.table.insert(from, ctx.current_step());
+            }
+        }
+        self.flush(ctx);
+    }
+
+    fn on_tick(&mut self, ctx: &mut Context<CBRMessage>) {
+        self.flush(ctx);
+    }
 }
@@ -0,0 +1,25 @@
+use crate::model::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoutableMessage {
+    Request(RequestMessage),
+    Ack(u32),
+}
+
+impl Payload for RoutableMessage {
+    fn payload(&self) -> Option<u32> {
+        match self {
+            RoutableMessage::Request(m) => Some(m.id),
+            RoutableMessage::Ack(_) => None,
+        }
+    }
+}
+
+impl WireSize for RoutableMessage {
+    fn wire_size(&self) -> u32 {
+        1 + match self {
+            RoutableMessage::Request(m) => m.wire_size(),
+            RoutableMessage::Ack(_) => 4,
+        }
+    }
+}
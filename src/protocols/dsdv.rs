@@ -1,6 +1,7 @@
 use crate::model::*;
-use std::collections::HashMap;
-use std::env;
+use crate::protocols::common::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct RoutingEntry {
@@ -10,110 +11,138 @@ pub struct RoutingEntry {
 }
 
 const DSDV_HEARTBEAT_PERIOD: u32 = 500;
-const DSDV_RETRY_PERIOD: u32 = 1 * 1000; /* 1 second */
+const DSDV_RETRY_PERIOD: u32 = 1000; /* 1 second */
 type RoutingTable = HashMap<u32, RoutingEntry>;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum RoutableMessage {
-    Request(RequestMessage),
-    Ack(u32),
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DSDVParams {
+    pub heartbeat_period: u32,
+    // steps before a request that wasn't acked is sent again
+    pub retry_period: u32,
+    // prefer shorter routes over fresher ones, set in the scenario file
+    pub shortest_path: bool,
 }
 
+impl Default for DSDVParams {
+    fn default() -> Self {
+        DSDVParams {
+            heartbeat_period: DSDV_HEARTBEAT_PERIOD,
+            retry_period: DSDV_RETRY_PERIOD,
+            shortest_path: false,
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub enum DSDVMessage {
     HeartBeat((RoutingTable, u32 /* from */)),
-    RoutingRequest((RoutableMessage, u32 /* rerouting_agent */, u32 /* destination */)),
+    RoutingRequest((RoutableMessage, u32 /* destination */)),
 }
 
+impl Payload for DSDVMessage {
+    fn payload(&self) -> Option<u32> {
+        match self {
+            DSDVMessage::HeartBeat(_) => None,
+            DSDVMessage::RoutingRequest((rm, _)) => rm.payload(),
+        }
+    }
+}
 
-pub async fn dsdv_actor(my_id: u32, mut ctx: Context<DSDVMessage>) {
-    let mut last_transmission = ctx.current_step();
-    let mut table = RoutingTable::from([
-        (my_id, RoutingEntry { metric: 0, next_hop: my_id, sequence_number: last_transmission }),
-    ]);
-    ctx.send(MessageType::Comm(DSDVMessage::HeartBeat((table.clone(), my_id))));
-
-    log::info!("worker {} started", my_id);
+impl WireSize for RoutingEntry {
+    fn wire_size(&self) -> u32 {
+        // sequence number, next hop and the metric
+        4 + 4 + 8
+    }
+}
 
-    let mut messages_to_send = Vec::<(RoutableMessage, u32 /* destination */)>::new();
-    let mut retries = HashMap::<u32, (RequestMessage, i32 /* last_sent */)>::new();
+impl WireSize for DSDVMessage {
+    fn wire_size(&self) -> u32 {
+        1 + match self {
+            // the sender, then the destination and the entry for every row of
+            // the table
+            DSDVMessage::HeartBeat((table, _)) => {
+                4 + table
+                    .values()
+                    .map(|entry| 4 + entry.wire_size())
+                    .sum::<u32>()
+            }
+            DSDVMessage::RoutingRequest((rm, _)) => rm.wire_size() + 4,
+        }
+    }
+}
 
+pub struct DSDVNode {
+    id: u32,
+    params: DSDVParams,
+    last_transmission: u32,
+    table: RoutingTable,
+    messages_to_send: Vec<(RoutableMessage, u32 /* destination */)>,
+    retries: BTreeMap<u32, (RequestMessage, i32 /* last_sent */)>,
+}
 
-    while let Ok(event) = ctx.read_for(10).await {
-        match event {
-            Some(m) => match m.data {
-                MessageType::Request(m) => {
-                    retries.insert(m.id, (m.clone(), -(DSDV_RETRY_PERIOD as i32)));
+impl DSDVNode {
+    fn update_table(&mut self, other_table: RoutingTable, from: u32) {
+        let table = &mut self.table;
+        for (dst, entry) in other_table.iter() {
+            let mut shall_update_entry = !table.contains_key(dst);
+            if !shall_update_entry {
+                if self.params.shortest_path {
+                    shall_update_entry |= table.get(dst).unwrap().metric > entry.metric + 1;
+                } else {
+                    shall_update_entry |= table.get(dst).unwrap().sequence_number
+                        < entry.sequence_number
+                        || (table.get(dst).unwrap().sequence_number == entry.sequence_number
+                            && table.get(dst).unwrap().metric > entry.metric + 1);
                 }
-                MessageType::Comm(m) => match m {
-                    DSDVMessage::RoutingRequest((rm, rerouting_agent, destination)) => {
-                        // We are not supposed to see this message
-                        if rerouting_agent == my_id {
-                            if destination == my_id {
-                                // This message has achieved its addressee
-                                log::warn!("Achieved its dest: {:?} {} {}", rm, rerouting_agent, destination);
-                                match rm {
-                                    RoutableMessage::Request(rm) => {
-                                        ctx.send(MessageType::Request(rm.clone()));
-                                        messages_to_send.push((RoutableMessage::Ack(rm.id), rm.from));
-                                    }
-                                    RoutableMessage::Ack(message_id) => {
-                                        retries.remove(&message_id);
-                                    }
-                                }
-                            } else {
-                                // Reroute it to someone else
-                                messages_to_send.push((rm.clone(), destination));
-                            }
-                        }
-                    }
-                    DSDVMessage::HeartBeat((other_table, from)) => {
-                        for (dst, entry) in other_table.iter() {
-                            let mut shall_update_entry = !table.contains_key(dst);
-                            if !shall_update_entry {
-                                if env::var("DSDV_SHORTEST_PATH").is_ok() {
-                                    shall_update_entry |= table.get(dst).unwrap().metric > entry.metric + 1;
-                                } else {
-                                    shall_update_entry |= table.get(dst).unwrap().sequence_number < entry.sequence_number ||
-                                        (table.get(dst).unwrap().sequence_number == entry.sequence_number && table.get(dst).unwrap().metric > entry.metric + 1);
-                                }
-                            }
-                            if shall_update_entry {
-                                let mut new_entry = entry.clone();
-                                new_entry.next_hop = from;
-                                new_entry.metric = entry.metric + 1;
-                                table.insert(dst.clone(), new_entry);
-                            }
-                        }
-                    }
-                },
-            },
-            None => {}
-        }
-        for (_, (rm, last_sent)) in retries.iter_mut() {
-            if (ctx.current_step() as i32) - *last_sent >= DSDV_RETRY_PERIOD as i32 {
-                messages_to_send.push((RoutableMessage::Request(rm.clone()), rm.to));
+            }
+            if shall_update_entry {
+                let mut new_entry = entry.clone();
+                new_entry.next_hop = from;
+                new_entry.metric = entry.metric + 1;
+                table.insert(*dst, new_entry);
             }
         }
-        log::info!("Size of mq: {} (agent {}) [{:?}]", messages_to_send.len(), my_id, messages_to_send);
+    }
 
+    // sends everything that is due at the current step and schedules the next
+    // heartbeat or retry
+    fn flush(&mut self, ctx: &mut Context<DSDVMessage>) {
+        let messages_to_send = &mut self.messages_to_send;
+        for (_, (rm, last_sent)) in self.retries.iter_mut() {
+            if (ctx.current_step() as i32) - *last_sent >= self.params.retry_period as i32 {
+                messages_to_send.push((RoutableMessage::Request(*rm), rm.to));
+            }
+        }
+        log::info!(
+            "Size of mq: {} (agent {}) [{:?}]",
+            messages_to_send.len(),
+            self.id,
+            messages_to_send
+        );
 
         // Deduplicate messages
         messages_to_send.sort_by_key(|(rm, _)| match rm {
             RoutableMessage::Request(rm) => rm.id,
-            RoutableMessage::Ack(id) => *id
+            RoutableMessage::Ack(id) => *id,
         });
         messages_to_send.dedup();
         // Send all enqueued on this step messages
         let mut unsent_messages = Vec::<(RoutableMessage, u32 /* destination */)>::default();
         for (msg, destination) in messages_to_send.iter() {
-            if table.contains_key(&destination) {
-                ctx.send(MessageType::Comm(DSDVMessage::RoutingRequest((msg.clone(), table.get(&destination).unwrap().next_hop, *destination))));
+            if let Some(entry) = self.table.get(destination) {
+                ctx.unicast(
+                    entry.next_hop,
+                    DSDVMessage::RoutingRequest((*msg, *destination)),
+                );
                 match msg {
                     RoutableMessage::Request(rm) => {
-                        if retries.contains_key(&rm.id) {
-                            retries.entry(rm.id).and_modify(|(_, last_transmission)| { *last_transmission = ctx.current_step() as i32; });
+                        if self.retries.contains_key(&rm.id) {
+                            self.retries
+                                .entry(rm.id)
+                                .and_modify(|(_, last_transmission)| {
+                                    *last_transmission = ctx.current_step() as i32;
+                                });
                         }
                     }
                     RoutableMessage::Ack(_) => {}
@@ -123,15 +152,103 @@ pub async fn dsdv_actor(my_id: u32, mut ctx: Context<DSDVMessage>) {
             }
         }
         messages_to_send.clear();
-        assert!(messages_to_send.is_empty());
         messages_to_send.append(&mut unsent_messages);
-        assert!(unsent_messages.is_empty());
         log::info!("Umq: {}", messages_to_send.len());
-        if ctx.current_step() - last_transmission >= DSDV_HEARTBEAT_PERIOD {
-            last_transmission = ctx.current_step();
-            table.insert(my_id, RoutingEntry { metric: 0, next_hop: my_id, sequence_number: last_transmission });
-            ctx.send(MessageType::Comm(DSDVMessage::HeartBeat((table.clone(), my_id))));
+        if ctx.current_step() - self.last_transmission >= self.params.heartbeat_period {
+            self.last_transmission = ctx.current_step();
+            self.table.insert(
+                self.id,
+                RoutingEntry {
+                    metric: 0,
+                    next_hop: self.id,
+                    sequence_number: self.last_transmission,
+                },
+            );
+            ctx.send(MessageType::Comm(DSDVMessage::HeartBeat((
+                self.table.clone(),
+                self.id,
+            ))));
+        }
+
+        // messages without a route are retried once a heartbeat brings one,
+        // so only the retries that were actually sent need a timer
+        let mut next = self.last_transmission + self.params.heartbeat_period;
+        for (_, last_sent) in self.retries.values() {
+            let at = *last_sent + self.params.retry_period as i32;
+            if at > ctx.current_step() as i32 {
+                next = next.min(at as u32);
+            }
+        }
+        ctx.wake_at(next);
+    }
+}
+
+impl Protocol for DSDVNode {
+    type Message = DSDVMessage;
+    type Params = DSDVParams;
+    const NAME: &'static str = "dsdv";
+
+    fn new(id: u32, params: &DSDVParams, ctx: &mut Context<DSDVMessage>) -> Self {
+        let last_transmission = ctx.current_step();
+        let table = RoutingTable::from([(
+            id,
+            RoutingEntry {
+                metric: 0,
+                next_hop: id,
+                sequence_number: last_transmission,
+            },
+        )]);
+        ctx.send(MessageType::Comm(DSDVMessage::HeartBeat((
+            table.clone(),
+            id,
+        ))));
+        ctx.wake_at(last_transmission + params.heartbeat_period);
+
+        log::info!("worker {} started", id);
+
+        DSDVNode {
+            id,
+            params: *params,
+            last_transmission,
+            table,
+            messages_to_send: Default::default(),
+            retries: Default::default(),
         }
     }
-    log::info!("worker {} stopped", my_id);
+
+    fn on_request(&mut self, ctx: &mut Context<DSDVMessage>, m: RequestMessage) {
+        self.retries
+            .insert(m.id, (m, -(self.params.retry_period as i32)));
+        self.flush(ctx);
+    }
+
+    fn on_receive(&mut self, ctx: &mut Context<DSDVMessage>, m: Message<DSDVMessage>) {
+        match m.data {
+            DSDVMessage::RoutingRequest((rm, destination)) => {
+                if destination == self.id {
+                    // This message has achieved its addressee
+                    log::debug!("Achieved its dest: {:?} {}", rm, destination);
+                    match rm {
+                        RoutableMessage::Request(rm) => {
+                            ctx.send(MessageType::Request(rm));
+                            self.messages_to_send
+                                .push((RoutableMessage::Ack(rm.id), rm.from));
+                        }
+                        RoutableMessage::Ack(message_id) => {
+                            self.retries.remove(&message_id);
+                        }
+                    }
+                } else {
+                    // Reroute it to someone else
+                    self.messages_to_send.push((rm, destination));
+                }
+            }
+            DSDVMessage::HeartBeat((other_table, from)) => self.update_table(other_table, from),
+        }
+        self.flush(ctx);
+    }
+
+    fn on_tick(&mut self, ctx: &mut Context<DSDVMessage>) {
+        self.flush(ctx);
+    }
 }
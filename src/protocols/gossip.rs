@@ -1,68 +1,132 @@
 use crate::model::*;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 const GOSSIP_TIMEOUT: u32 = 100;
 
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GossipParams {
+    // steps before a request that wasn't acked is broadcast again
+    pub timeout: u32,
+}
+
+impl Default for GossipParams {
+    fn default() -> Self {
+        GossipParams {
+            timeout: GOSSIP_TIMEOUT,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum GossipMessage {
     Request(RequestMessage),
     Ack(u32),
 }
 
-pub async fn gossip_actor(my_id: u32, mut ctx: Context<GossipMessage>) {
-    log::info!("worker {} started", my_id);
-    let mut history = HashMap::<u32, (GossipMessage, u32)>::default();
-    while let Ok(event) = ctx.read_for(10).await {
-        match event {
-            Some(m) => match m.data {
-                MessageType::Request(m) => {
-                    let gm = GossipMessage::Request(m);
-                    history.entry(m.id).or_insert((gm.clone(), 0));
-                }
-                MessageType::Comm(m) => match m {
-                    GossipMessage::Request(m) => {
-                        if m.to == my_id {
-                            ctx.send(MessageType::Request(m));
-                            history.insert(m.id, (GossipMessage::Ack(m.id), 0));
-                        } else {
-                            history
-                                .entry(m.id)
-                                .or_insert((GossipMessage::Request(m), 0));
-                        }
-                        // if history[id] == Ack
-                        if history
-                            .get(&m.id)
-                            .map(|x| {
-                                matches!(x.0, GossipMessage::Ack(_)) && x.1 != ctx.current_step()
-                            })
-                            .unwrap_or_default()
-                        {
-                            history.entry(m.id).and_modify(|m| m.1 = ctx.current_step());
-                            ctx.send(MessageType::Comm(GossipMessage::Ack(m.id)));
-                        }
-                    }
-                    GossipMessage::Ack(id) => {
-                        // if history[id] != Ack
-                        if !history
-                            .get(&id)
-                            .map(|x| matches!(x.0, GossipMessage::Ack(_)))
-                            .unwrap_or_default()
-                        {
-                            history.insert(id, (GossipMessage::Ack(id), ctx.current_step()));
-                            ctx.send(MessageType::Comm(GossipMessage::Ack(id)));
-                        }
-                    }
-                },
-            },
-            None => {}
+impl Payload for GossipMessage {
+    fn payload(&self) -> Option<u32> {
+        match self {
+            GossipMessage::Request(m) => Some(m.id),
+            GossipMessage::Ack(_) => None,
+        }
+    }
+}
+
+impl WireSize for GossipMessage {
+    fn wire_size(&self) -> u32 {
+        1 + match self {
+            GossipMessage::Request(m) => m.wire_size(),
+            GossipMessage::Ack(_) => 4,
         }
-        for (_, m) in history.iter_mut() {
-            if matches!(m.0, GossipMessage::Request(_)) && ctx.current_step() - m.1 > GOSSIP_TIMEOUT
-            {
+    }
+}
+
+pub struct GossipNode {
+    id: u32,
+    params: GossipParams,
+    history: BTreeMap<u32, (GossipMessage, u32)>,
+}
+
+impl GossipNode {
+    fn resend(&mut self, ctx: &mut Context<GossipMessage>) {
+        let mut next = None;
+        for (_, m) in self.history.iter_mut() {
+            if !matches!(m.0, GossipMessage::Request(_)) {
+                continue;
+            }
+            if ctx.current_step() - m.1 > self.params.timeout {
                 ctx.send(MessageType::Comm(m.0.clone()));
                 m.1 = ctx.current_step();
             }
+            let at = m.1 + self.params.timeout + 1;
+            next = Some(next.map_or(at, |next: u32| next.min(at)));
+        }
+        if let Some(at) = next {
+            ctx.wake_at(at);
         }
     }
-    log::info!("worker {} stopped", my_id);
+}
+
+impl Protocol for GossipNode {
+    type Message = GossipMessage;
+    type Params = GossipParams;
+    const NAME: &'static str = "gossip";
+
+    fn new(id: u32, params: &GossipParams, _ctx: &mut Context<GossipMessage>) -> Self {
+        log::info!("worker {} started", id);
+        GossipNode {
+            id,
+            params: *params,
+            history: Default::default(),
+        }
+    }
+
+    fn on_request(&mut self, ctx: &mut Context<GossipMessage>, m: RequestMessage) {
+        let gm = GossipMessage::Request(m);
+        self.history.entry(m.id).or_insert((gm, 0));
+        self.resend(ctx);
+    }
+
+    fn on_receive(&mut self, ctx: &mut Context<GossipMessage>, m: Message<GossipMessage>) {
+        let history = &mut self.history;
+        match m.data {
+            GossipMessage::Request(m) => {
+                if m.to == self.id {
+                    ctx.send(MessageType::Request(m));
+                    history.insert(m.id, (GossipMessage::Ack(m.id), 0));
+                } else {
+                    history
+                        .entry(m.id)
+                        .or_insert((GossipMessage::Request(m), 0));
+                }
+                // if history[id] == Ack
+                if history
+                    .get(&m.id)
+                    .map(|x| matches!(x.0, GossipMessage::Ack(_)) && x.1 != ctx.current_step())
+                    .unwrap_or_default()
+                {
+                    history.entry(m.id).and_modify(|m| m.1 = ctx.current_step());
+                    ctx.send(MessageType::Comm(GossipMessage::Ack(m.id)));
+                }
+            }
+            GossipMessage::Ack(id) => {
+                // if history[id] != Ack
+                if !history
+                    .get(&id)
+                    .map(|x| matches!(x.0, GossipMessage::Ack(_)))
+                    .unwrap_or_default()
+                {
+                    history.insert(id, (GossipMessage::Ack(id), ctx.current_step()));
+                    ctx.send(MessageType::Comm(GossipMessage::Ack(id)));
+                }
+            }
+        }
+        self.resend(ctx);
+    }
+
+    fn on_tick(&mut self, ctx: &mut Context<GossipMessage>) {
+        self.resend(ctx);
+    }
 }
@@ -1,3 +1,6 @@
+pub mod common;
+pub use common::*;
+
 pub mod gossip;
 pub use gossip::*;
 
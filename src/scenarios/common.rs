@@ -1,39 +1,17 @@
 use rand::Rng;
 
 use crate::model::*;
-use crate::protocols::*;
 
-pub fn generate_gossip_model<R>(size: u32, rng: R) -> Model<GossipMessage, R> {
-    let (model, contexts) = Model::new(size, rng);
-    for (id, ctx) in contexts.into_iter().enumerate() {
-        tokio::spawn(async move {
-            gossip_actor(id as u32, ctx).await;
-        });
-    }
-    return model;
-}
-
-pub fn generate_dsdv_model<R>(size: u32, rng: R) -> Model<DSDVMessage, R> {
-    let (model, contexts) = Model::new(size, rng);
-    for (id, ctx) in contexts.into_iter().enumerate() {
-        tokio::spawn(async move {
-            dsdv_actor(id as u32, ctx).await;
-        });
-    }
-    return model;
-}
-
-pub fn generate_cbr_model<R>(size: u32, rng: R) -> Model<CBRMessage, R> {
-    let (model, contexts) = Model::new(size, rng);
-    for (id, ctx) in contexts.into_iter().enumerate() {
-        tokio::spawn(async move {
-            cbr_actor(id as u32, ctx).await;
-        });
-    }
-    return model;
+/// Bursts of `length` steps on average every `gap + length` steps, losing
+/// messages with the chance of `loss`. None if `length` is 0
+pub fn bursts(gap: f64, length: f64, loss: f64) -> Option<GilbertElliott> {
+    (length > 0.).then(|| GilbertElliott {
+        bad_loss: loss,
+        ..GilbertElliott::new(gap, length)
+    })
 }
 
-pub fn send_batch<T: Clone + core::fmt::Debug, R: Rng>(model: &mut Model<T, R>, count: u32) {
+pub fn send_batch<P: Protocol, R: Rng>(model: &mut Model<P, R>, count: u32) {
     for _ in 0..count {
         model.request_random();
     }
@@ -1,39 +1,102 @@
-use rand::thread_rng;
+use rand::{rngs::StdRng, SeedableRng};
 
+#[cfg(test)]
+use crate::export::*;
 use crate::model::*;
+#[cfg(test)]
 use crate::protocols::*;
 use crate::scenarios::*;
 
+use clap::Args;
+use serde::{Deserialize, Serialize};
+
 const STEPS: i32 = 10000;
+const DEFAULT_NODES_COUNT: u32 = 10;
+const DEFAULT_CONNECTION_PROBABILITY: f64 = 0.1;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Args)]
+#[serde(default)]
+pub struct CompleteModelParams {
+    /// Steps to run for at most
+    #[arg(long, default_value_t = STEPS as u32)]
+    pub steps_count: u32,
+    #[arg(long, default_value_t = DEFAULT_NODES_COUNT, value_parser = clap::value_parser!(u32).range(2..))]
+    pub nodes_count: u32,
+    /// Messages requested between random nodes at the start
+    #[arg(long, default_value_t = DEFAULT_NODES_COUNT)]
+    pub messages_count: u32,
+    /// Probability of a message getting through any of the links
+    #[arg(long, default_value_t = DEFAULT_CONNECTION_PROBABILITY)]
+    pub connection_probability: f64,
+    #[arg(long)]
+    pub seed: Option<u64>,
+}
 
-fn init_simple<T: Clone + core::fmt::Debug, R>(
+impl Default for CompleteModelParams {
+    fn default() -> Self {
+        CompleteModelParams {
+            steps_count: STEPS as u32,
+            nodes_count: DEFAULT_NODES_COUNT,
+            messages_count: DEFAULT_NODES_COUNT,
+            connection_probability: DEFAULT_CONNECTION_PROBABILITY,
+            seed: None,
+        }
+    }
+}
+
+fn init_complete<P: Protocol, R>(
     size: u32,
+    probability: f64,
+    protocol: P::Params,
     rng: R,
-) -> (Model<T, R>, Vec<Context<T>>) {
-    let (mut model, contexts) = Model::new(size, rng);
-    for i in 0..size {
-        for j in i + 1..size {
-            model.conn.update_both(i, j, 0.1, 0);
-        }
+) -> Model<P, R> {
+    let mut model = Model::with_params(size, protocol, rng);
+    complete(size).fill(&mut model.conn, Link::new(probability as f32, 0));
+    model
+}
+
+#[cfg(test)]
+fn init_simple<P: Protocol, R>(size: u32, rng: R) -> Model<P, R> {
+    init_complete(
+        size,
+        DEFAULT_CONNECTION_PROBABILITY,
+        Default::default(),
+        rng,
+    )
+}
+
+pub fn run_complete<P: Protocol>(
+    params: &CompleteModelParams,
+    protocol: &P::Params,
+    seed: u64,
+    trace: Option<Trace>,
+) -> Result<Stats, anyhow::Error> {
+    let mut model: Model<P, _> = init_complete(
+        params.nodes_count,
+        params.connection_probability,
+        protocol.clone(),
+        StdRng::seed_from_u64(seed),
+    );
+    if let Some(trace) = trace {
+        model.set_trace(trace);
+    }
+    send_batch(&mut model, params.messages_count);
+    while model.current_step() < params.steps_count && !model.stats.all_delivered() {
+        model.advance(params.steps_count);
     }
-    (model, contexts)
+    model.take_trace().map(Trace::finish).transpose()?;
+    Ok(model.stats)
 }
 
-#[tokio::test]
-async fn gossip() {
+#[test]
+fn gossip() {
     let _ = env_logger::builder().try_init();
 
     const SIZE: u32 = 10;
 
-    let (mut model, contexts) = init_simple(SIZE, thread_rng());
+    let mut model: Model<GossipNode, _> = init_simple(SIZE, rand::thread_rng());
     send_batch(&mut model, SIZE);
 
-    for (id, ctx) in contexts.into_iter().enumerate() {
-        tokio::spawn(async move {
-            gossip_actor(id as u32, ctx).await;
-        });
-    }
-
     // basically works like a timeout
     for _ in 0..STEPS {
         // if all messages that were requested are delivered, break
@@ -53,8 +116,276 @@ async fn gossip() {
         // also you can send additional messages, if you want, like
         // model.request_random();
 
-        model.step().await;
+        model.step();
     }
 
     log::info!("{:?}", model.stats);
 }
+
+#[cfg(test)]
+fn run_seeded<P: Protocol>(seed: u64, size: u32) -> Stats {
+    let mut model: Model<P, _> = init_simple(size, StdRng::seed_from_u64(seed));
+    send_batch(&mut model, size);
+    for _ in 0..STEPS {
+        if model.stats.all_delivered() {
+            break;
+        }
+        model.step();
+    }
+    model.stats
+}
+
+#[test]
+fn deterministic() {
+    let _ = env_logger::builder().try_init();
+
+    const SIZE: u32 = 10;
+
+    for seed in 0..3 {
+        assert_eq!(
+            run_seeded::<GossipNode>(seed, SIZE),
+            run_seeded::<GossipNode>(seed, SIZE)
+        );
+        assert_eq!(
+            run_seeded::<DSDVNode>(seed, SIZE),
+            run_seeded::<DSDVNode>(seed, SIZE)
+        );
+        assert_eq!(
+            run_seeded::<CBRNode>(seed, SIZE),
+            run_seeded::<CBRNode>(seed, SIZE)
+        );
+    }
+}
+
+#[test]
+fn cbr_relays_once() {
+    let _ = env_logger::builder().try_init();
+
+    const SIZE: u32 = 12;
+
+    // every node hears every other one, a request to a node nobody heard of
+    // yet goes everywhere, but only once
+    let mut model: Model<CBRNode, _> =
+        init_complete(SIZE, 1., Default::default(), StdRng::seed_from_u64(0));
+    model.request_message(0, 1);
+    model.run_until(STEPS as u32);
+    assert!(model.stats.all_delivered());
+    assert!(
+        model.stats.data.transmissions <= SIZE,
+        "{}",
+        model.stats.data.transmissions
+    );
+}
+
+#[test]
+fn collisions() {
+    let _ = env_logger::builder().try_init();
+
+    const SIZE: u32 = 10;
+
+    let mut model: Model<GossipNode, _> = init_simple(SIZE, StdRng::seed_from_u64(0));
+    model.set_mac(MacParams::default());
+    send_batch(&mut model, SIZE);
+    model.run_until(STEPS as u32);
+    assert!(model.stats.collisions > 0);
+    assert_eq!(model.stats.backoffs, 0);
+
+    let mut model: Model<GossipNode, _> = init_simple(SIZE, StdRng::seed_from_u64(0));
+    model.set_mac(MacParams {
+        csma: Some(CsmaParams {
+            window: 10,
+            max_attempts: 5,
+        }),
+    });
+    send_batch(&mut model, SIZE);
+    model.run_until(STEPS as u32);
+    assert!(model.stats.backoffs > 0);
+}
+
+#[test]
+fn battery() {
+    let _ = env_logger::builder().try_init();
+
+    const SIZE: u32 = 10;
+
+    let mut model: Model<GossipNode, _> = init_simple(SIZE, StdRng::seed_from_u64(0));
+    model.set_energy(EnergyParams {
+        capacity: 1.,
+        transmit: 0.001,
+        receive: 0.001,
+        idle: 0.0001,
+        sleep: 0.,
+    });
+    send_batch(&mut model, SIZE);
+    model.run_until(STEPS as u32);
+    // idling alone drains the battery in 10000 steps
+    assert_eq!(model.stats.dead, SIZE);
+    let first_death = model.stats.first_death.unwrap();
+    assert!(first_death < 10000);
+    assert!(first_death <= model.stats.lifetime.unwrap());
+    assert_eq!(model.stats.energy.len(), SIZE as usize);
+    assert!(model.stats.energy.iter().all(|consumed| *consumed >= 1.));
+    assert!((0..SIZE).all(|id| !model.alive(id)));
+}
+
+#[test]
+fn crash() {
+    let _ = env_logger::builder().try_init();
+
+    const SIZE: u32 = 10;
+
+    let mut model: Model<GossipNode, _> = init_simple(SIZE, StdRng::seed_from_u64(0));
+    model.kill_node(1);
+    model.request_message(0, 1);
+    model.run_until(STEPS as u32);
+    assert!(!model.alive(1));
+    assert!(model.stats.dropped > 0);
+    assert_eq!(model.stats.delivered, 0);
+
+    // the request is still in the history of the others
+    model.restart_node(1, false);
+    model.run_until(2 * STEPS as u32);
+    assert!(model.alive(1));
+    assert!(model.stats.all_delivered());
+}
+
+#[test]
+fn stats() {
+    let _ = env_logger::builder().try_init();
+
+    const SIZE: u32 = 10;
+
+    let stats = run_seeded::<GossipNode>(0, SIZE);
+    assert!(stats.all_delivered());
+    assert_eq!(stats.delivery_ratio(), 1.);
+    assert!(stats.messages.values().all(|stat| stat.hops >= 1));
+    assert!(stats.avg_hops().unwrap() >= 1.);
+    let p50 = stats.latency_percentile(50.).unwrap();
+    let p90 = stats.latency_percentile(90.).unwrap();
+    let p99 = stats.latency_percentile(99.).unwrap();
+    assert!(p50 <= p90 && p90 <= p99);
+    assert_eq!(
+        p99,
+        stats
+            .messages
+            .values()
+            .map(|stat| stat.steps)
+            .max()
+            .unwrap()
+    );
+    assert!(stats.data.transmissions > 0 && stats.control.transmissions > 0);
+    assert_eq!(
+        stats.data.transmissions + stats.control.transmissions,
+        stats.broadcasts + stats.unicasts
+    );
+    assert_eq!(stats.data.bytes + stats.control.bytes, stats.bytes_sent);
+    assert_eq!(
+        stats.transmitted.iter().sum::<u32>(),
+        stats.broadcasts + stats.unicasts
+    );
+    assert_eq!(stats.received.iter().sum::<u32>(), stats.total);
+
+    // nothing delivered yet
+    let mut model: Model<GossipNode, _> = init_simple(SIZE, StdRng::seed_from_u64(0));
+    assert_eq!(model.stats.delivery_ratio(), 1.);
+    send_batch(&mut model, SIZE);
+    assert_eq!(model.stats.delivery_ratio(), 0.);
+    assert_eq!(model.stats.avg_delivery_time(), None);
+    assert_eq!(model.stats.latency_percentile(50.), None);
+}
+
+#[test]
+fn export() {
+    let _ = env_logger::builder().try_init();
+
+    const SIZE: u32 = 10;
+
+    let dir = std::env::temp_dir().join(format!("radio-message-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (csv, jsonl) = (dir.join("runs.csv"), dir.join("runs.jsonl"));
+
+    let records: Vec<_> = (0..2)
+        .map(|seed| Record::new::<GossipNode>(seed, SIZE, run_seeded::<GossipNode>(seed, SIZE)))
+        .collect();
+    for record in records.iter() {
+        // reopened every time, like runs resumed later
+        Exporter::create(&csv).unwrap().write(record).unwrap();
+        Exporter::create(&jsonl).unwrap().write(record).unwrap();
+    }
+
+    let csv = std::fs::read_to_string(csv).unwrap();
+    let lines: Vec<_> = csv.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].starts_with("algorithm,sent,transmitted,received,avg_time,seed"));
+    assert!(lines[1].starts_with("gossip,10,"));
+
+    let jsonl = std::fs::read_to_string(jsonl).unwrap();
+    for (line, record) in jsonl.lines().zip(records.iter()) {
+        let read: Record<u32> = serde_json::from_str(line).unwrap();
+        assert_eq!(read.protocol, "gossip");
+        assert_eq!(read.seed, record.seed);
+        assert_eq!(read.stats, record.stats);
+    }
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn trace() {
+    let _ = env_logger::builder().try_init();
+
+    let dir = std::env::temp_dir().join(format!("radio-message-trace-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("run.tr");
+    let params = CompleteModelParams {
+        messages_count: 5,
+        ..Default::default()
+    };
+
+    let stats = run_complete::<DSDVNode>(&params, &Default::default(), 1, None).unwrap();
+    let trace = Trace::create(&path).unwrap();
+    let traced = run_complete::<DSDVNode>(&params, &Default::default(), 1, Some(trace)).unwrap();
+    // recording doesn't change the run
+    assert_eq!(stats, traced);
+
+    let file = std::io::BufReader::new(std::fs::File::open(&path).unwrap());
+    let events = read_trace(file).unwrap();
+    let count = |f: fn(&TraceEvent) -> bool| events.iter().filter(|e| f(e)).count() as u32;
+    assert_eq!(count(|e| matches!(e, TraceEvent::Request { .. })), 5);
+    assert_eq!(
+        count(|e| matches!(e, TraceEvent::Transmit { to: None, .. })),
+        stats.broadcasts
+    );
+    assert_eq!(
+        count(|e| matches!(e, TraceEvent::Transmit { to: Some(_), .. })),
+        stats.unicasts
+    );
+    assert_eq!(
+        count(|e| matches!(
+            e,
+            TraceEvent::Receive {
+                reception: Reception::Received,
+                ..
+            }
+        )),
+        stats.total
+    );
+    assert_eq!(
+        count(|e| matches!(e, TraceEvent::Link { at: Some(_), .. })),
+        count(|e| matches!(e, TraceEvent::Receive { .. }))
+    );
+    assert_eq!(
+        count(|e| matches!(
+            e,
+            TraceEvent::Deliver {
+                duplicate: false,
+                ..
+            }
+        )),
+        stats.delivered
+    );
+    assert!(events.windows(2).all(|w| w[0].step() <= w[1].step()));
+    for event in events {
+        assert_eq!(event.to_string().parse::<TraceEvent>().unwrap(), event);
+    }
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,213 @@
+use clap::ValueEnum;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::export::*;
+use crate::model::Trace;
+use crate::protocols::*;
+use crate::scenarios::complete::*;
+use crate::scenarios::moving::*;
+use crate::scenarios::sparse::*;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ScenarioParams {
+    Complete(CompleteModelParams),
+    Moving(Box<MovingModelParams>),
+    Sparse(SparseModelParams),
+}
+
+impl ScenarioParams {
+    pub fn seed(&self) -> Option<u64> {
+        match self {
+            ScenarioParams::Complete(params) => params.seed,
+            ScenarioParams::Moving(params) => params.seed,
+            ScenarioParams::Sparse(params) => params.seed,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(tag = "name", rename_all = "lowercase")]
+pub enum ProtocolParams {
+    Gossip(GossipParams),
+    Dsdv(DSDVParams),
+    Cbr(CBRParams),
+}
+
+/// Protocol picked on the command line
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum ProtocolName {
+    Gossip,
+    Dsdv,
+    Cbr,
+}
+
+impl ProtocolName {
+    // the flags don't set the protocol parameters, the scenario files do
+    pub fn params(self) -> ProtocolParams {
+        match self {
+            ProtocolName::Gossip => ProtocolParams::Gossip(Default::default()),
+            ProtocolName::Dsdv => ProtocolParams::Dsdv(Default::default()),
+            ProtocolName::Cbr => ProtocolParams::Cbr(Default::default()),
+        }
+    }
+}
+
+/// What a run is made of, the parameters of its `Record`
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Setup<'a> {
+    #[serde(flatten)]
+    pub scenario: &'a ScenarioParams,
+    // a single column, so runs of different protocols fit in one csv
+    pub protocol: &'a ProtocolParams,
+}
+
+/// An experiment as stored in a scenario file, like
+/// `experiments/moving-dsdv.json`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Experiment {
+    pub scenario: ScenarioParams,
+    pub protocol: ProtocolParams,
+    /// Run number i gets the seed of the scenario + i, or a random one if
+    /// there is no seed
+    #[serde(default = "one")]
+    pub runs: u64,
+    /// csv or jsonl file the records of the runs are appended to
+    #[serde(default)]
+    pub output: Option<PathBuf>,
+    /// File the trace of the run is written to, with several runs every one
+    /// gets its own with the seed added to the name
+    #[serde(default)]
+    pub trace: Option<PathBuf>,
+}
+
+pub(super) fn one() -> u64 {
+    1
+}
+
+// runs `$body` with `$p` being the protocol and `$params` its parameters
+macro_rules! with_protocol {
+    ($protocol:expr, $p:ident, $params:ident => $body:expr) => {
+        match $protocol {
+            ProtocolParams::Gossip($params) => {
+                type $p = GossipNode;
+                $body
+            }
+            ProtocolParams::Dsdv($params) => {
+                type $p = DSDVNode;
+                $body
+            }
+            ProtocolParams::Cbr($params) => {
+                type $p = CBRNode;
+                $body
+            }
+        }
+    };
+}
+
+impl Experiment {
+    pub fn load(path: impl AsRef<Path>) -> Result<Experiment, anyhow::Error> {
+        let path = path.as_ref();
+        let file = std::fs::File::open(path)
+            .map_err(|err| anyhow::anyhow!("can't open {}: {}", path.display(), err))?;
+        Ok(serde_json::from_reader(std::io::BufReader::new(file))?)
+    }
+
+    pub fn setup(&self) -> Setup<'_> {
+        Setup {
+            scenario: &self.scenario,
+            protocol: &self.protocol,
+        }
+    }
+
+    pub fn seed(&self, run: u64) -> u64 {
+        match self.scenario.seed() {
+            Some(seed) => seed.wrapping_add(run),
+            None => rand::thread_rng().gen(),
+        }
+    }
+
+    /// Where the trace of the run with `seed` goes, if anywhere
+    pub fn trace_path(&self, seed: u64) -> Option<PathBuf> {
+        let path = self.trace.as_ref()?;
+        if self.runs <= 1 {
+            return Some(path.clone());
+        }
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let name = match path.extension() {
+            Some(ext) => format!("{}-{}.{}", stem, seed, ext.to_string_lossy()),
+            None => format!("{}-{}", stem, seed),
+        };
+        Some(path.with_file_name(name))
+    }
+
+    pub fn run_once(&self, seed: u64) -> Result<Record<Setup<'_>>, anyhow::Error> {
+        let trace = self.trace_path(seed).map(Trace::create).transpose()?;
+        with_protocol!(&self.protocol, P, protocol => {
+            let stats = match &self.scenario {
+                ScenarioParams::Complete(params) => {
+                    run_complete::<P>(params, protocol, seed, trace)?
+                }
+                ScenarioParams::Moving(params) => run_moving::<P>(params, protocol, seed, trace)?,
+                ScenarioParams::Sparse(params) => run_sparse::<P>(params, protocol, seed, trace)?,
+            };
+            Ok(Record::new::<P>(seed, self.setup(), stats))
+        })
+    }
+
+    /// Runs all the runs one after another, prints a line for each of them
+    /// and appends their records to the output
+    pub fn run(&self) -> Result<(), anyhow::Error> {
+        let mut export = self.output.as_ref().map(Exporter::create).transpose()?;
+        for run in 0..self.runs {
+            let record = self.run_once(self.seed(run))?;
+            println!("{}", record.summary());
+            if let Some(export) = &mut export {
+                export.write(&record)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn experiments() {
+    let _ = env_logger::builder().try_init();
+
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("experiments");
+    let mut found = 0;
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        let name = path.file_name().unwrap().to_str().unwrap();
+        if name.starts_with("sweep") {
+            crate::scenarios::sweep::Sweep::load(&path)
+                .unwrap()
+                .jobs()
+                .unwrap();
+        } else if name.ends_with(".json") {
+            Experiment::load(&path).unwrap();
+            found += 1;
+        }
+    }
+    assert!(found > 0);
+
+    let experiment: Experiment = serde_json::from_str(
+        r#"{
+            "scenario": { "type": "complete", "nodes_count": 5, "seed": 3 },
+            "protocol": { "name": "gossip", "timeout": 50 },
+            "runs": 2
+        }"#,
+    )
+    .unwrap();
+    assert!(matches!(
+        experiment.protocol,
+        ProtocolParams::Gossip(GossipParams { timeout: 50 })
+    ));
+    let first = experiment.run_once(experiment.seed(0)).unwrap();
+    assert_eq!(first.seed, 3);
+    assert_eq!(first.stats, experiment.run_once(3).unwrap().stats);
+    assert!(first.stats.all_delivered());
+    assert_eq!(first.stats.messages.len(), 10);
+}
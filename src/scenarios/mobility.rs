@@ -0,0 +1,826 @@
+use std::f64::consts::{PI, TAU};
+
+use clap::ValueEnum;
+use euclid::*;
+use rand::prelude::*;
+use rand_distr::{StandardNormal, Uniform};
+use serde::{Deserialize, Serialize};
+
+use crate::scenarios::movements::*;
+use crate::scenarios::moving::MovingModelParams;
+use crate::scenarios::obstacles::*;
+
+// part of the field near the borders where Gauss-Markov agents turn to the middle
+const GAUSS_MARKOV_MARGIN: f64 = 0.1;
+// random spots tried before giving up on getting around the obstacles
+const MAX_TRIES: u32 = 1000;
+// steps a waypoint agent shut in by the obstacles waits before it looks for
+// a way out again
+const SHUT_IN_WAIT: u32 = 1000;
+
+#[derive(Copy, Clone)]
+pub struct Agent {
+    pub position: Point2D<f64, UnknownUnit>,
+    pub destination: Point2D<f64, UnknownUnit>,
+    pub velocity: f64, // Distance traversed by one person in 1 step
+}
+
+/// How the agents go around the field. They always move in a straight line
+/// to their destination, a model only picks the destinations and the speeds
+pub trait Mobility {
+    /// Where the agents start and head to
+    fn place(&mut self, count: u32, rng: &mut dyn RngCore) -> Vec<Agent>;
+    /// Moves the agents by a step, returns the ones that changed course
+    fn step(&mut self, agents: &mut [Agent], rng: &mut dyn RngCore) -> Vec<usize>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum MobilityModel {
+    /// To a random spot of the field, then a pause and another one
+    Waypoint,
+    /// In a random direction for a while, bouncing off the borders
+    Walk,
+    /// In a random direction up to the border, then a pause and another one
+    Direction,
+    /// Speed and direction change a bit every while, keeping some of the
+    /// previous ones
+    GaussMarkov,
+    /// Along the streets of a grid, turning at the crossings
+    Manhattan,
+    /// In groups following a reference point, which moves like a waypoint
+    Group,
+}
+
+/// The model of the parameters, or the recorded movements if they are given
+pub fn mobility(params: &MovingModelParams) -> Result<Box<dyn Mobility>, anyhow::Error> {
+    if let Some(path) = &params.movements {
+        let movements = Movements::load(path)?;
+        if movements.nodes() < params.agents_count {
+            anyhow::bail!(
+                "{} has {} nodes, not enough for {} agents",
+                path.display(),
+                movements.nodes(),
+                params.agents_count
+            );
+        }
+        if movements.extent() > params.field_size {
+            log::warn!(
+                "{} goes out of the field of {} meters",
+                path.display(),
+                params.field_size
+            );
+        }
+        return Ok(Box::new(Recorded::new(movements)));
+    }
+    if !params.obstacles.is_empty() && params.mobility != MobilityModel::Waypoint {
+        log::warn!("only the waypoint agents go around the obstacles");
+    }
+    Ok(match params.mobility {
+        MobilityModel::Waypoint => Box::new(RandomWaypoint::new(params)),
+        MobilityModel::Walk => Box::new(RandomWalk::new(params)),
+        MobilityModel::Direction => Box::new(RandomDirection::new(params)),
+        MobilityModel::GaussMarkov => Box::new(GaussMarkov::new(params)),
+        MobilityModel::Manhattan => Box::new(Manhattan::new(params)),
+        MobilityModel::Group => Box::new(Group::new(params)),
+    })
+}
+
+// moves the agent for `steps` towards its destination, returns the part of the
+// steps left when it gets there
+fn travel(agent: &mut Agent, steps: f64) -> Option<f64> {
+    let remaining_dist = agent.destination.distance_to(agent.position);
+    if remaining_dist > (steps * agent.velocity) {
+        let mut direction = agent.destination - agent.position;
+        direction = direction / direction.length() * steps * agent.velocity;
+        agent.position += direction;
+        None
+    } else {
+        agent.position = agent.destination;
+        Some(steps - remaining_dist / agent.velocity)
+    }
+}
+
+// folds a coordinate that went past a border back into the field
+fn reflect(mut x: f64, size: f64) -> f64 {
+    if x < 0. {
+        x = -x;
+    }
+    if x > size {
+        x = 2. * size - x;
+    }
+    x.clamp(0., size)
+}
+
+fn push_turned(turned: &mut Vec<usize>, id: usize) {
+    if turned.last() != Some(&id) {
+        turned.push(id);
+    }
+}
+
+/// Random waypoint: to a random spot at a random speed, then a random pause
+/// of up to `pause_time` steps. The spots are outside the obstacles and the
+/// agents go around them on the shortest way
+pub struct RandomWaypoint {
+    field_random: Uniform<f64>,
+    speed_random: Uniform<f64>,
+    pause_time: u32,
+    // steps left to wait for every agent
+    paused: Vec<u32>,
+    obstacles: Obstacles,
+    // spots left on the way to the destination of every agent, the next last
+    routes: Vec<Vec<Point2D<f64, UnknownUnit>>>,
+}
+
+impl RandomWaypoint {
+    pub fn new(params: &MovingModelParams) -> Self {
+        RandomWaypoint {
+            field_random: Uniform::new(0.0, params.field_size),
+            speed_random: Uniform::new(params.min_velocity, params.max_velocity),
+            pause_time: params.pause_time,
+            paused: Vec::new(),
+            obstacles: Obstacles::new(&params.obstacles, params.field_size),
+            routes: Vec::new(),
+        }
+    }
+
+    fn random_spot(&self, rng: &mut dyn RngCore) -> Point2D<f64, UnknownUnit> {
+        point2(rng.sample(self.field_random), rng.sample(self.field_random))
+    }
+
+    // a random spot of the field outside the obstacles, none if they seem to
+    // cover all of it
+    fn spot(&self, rng: &mut dyn RngCore) -> Option<Point2D<f64, UnknownUnit>> {
+        (0..MAX_TRIES)
+            .map(|_| self.random_spot(rng))
+            .find(|&spot| !self.obstacles.inside(spot))
+    }
+
+    // a new destination and speed, false if there's no way anywhere and the
+    // agent has to stay where it is
+    fn head(&mut self, id: usize, agent: &mut Agent, rng: &mut dyn RngCore) -> bool {
+        let route = (0..MAX_TRIES).find_map(|_| {
+            let destination = self.random_spot(rng);
+            if self.obstacles.inside(destination) {
+                return None;
+            }
+            self.obstacles.route(agent.position, destination)
+        });
+        agent.velocity = rng.sample(self.speed_random);
+        self.routes[id].clear();
+        let Some(mut route) = route else {
+            agent.destination = agent.position;
+            return false;
+        };
+        route.reverse();
+        agent.destination = route.pop().unwrap();
+        self.routes[id] = route;
+        true
+    }
+
+    // looks for a new destination, an agent that's shut in waits a bit
+    // before it tries again
+    fn head_or_wait(&mut self, id: usize, agent: &mut Agent, rng: &mut dyn RngCore) -> bool {
+        let headed = self.head(id, agent, rng);
+        if !headed {
+            self.paused[id] = SHUT_IN_WAIT;
+        }
+        headed
+    }
+}
+
+impl Mobility for RandomWaypoint {
+    fn place(&mut self, count: u32, rng: &mut dyn RngCore) -> Vec<Agent> {
+        self.paused = vec![0; count as usize];
+        self.routes = vec![Vec::new(); count as usize];
+        let mut agents = Vec::with_capacity(count as usize);
+        for id in 0..count as usize {
+            let position = self.spot(rng).unwrap_or_else(|| {
+                log::warn!("no room outside the obstacles for agent {}", id);
+                self.random_spot(rng)
+            });
+            let mut agent = Agent {
+                position,
+                destination: position,
+                velocity: 0.,
+            };
+            self.head_or_wait(id, &mut agent, rng);
+            agents.push(agent);
+        }
+        agents
+    }
+
+    fn step(&mut self, agents: &mut [Agent], rng: &mut dyn RngCore) -> Vec<usize> {
+        let mut turned = Vec::new();
+        for (id, agent) in agents.iter_mut().enumerate() {
+            log::debug!(
+                "Position: {}\t{}",
+                id,
+                (agent.position - agent.destination).length()
+            );
+            if self.paused[id] > 0 {
+                self.paused[id] -= 1;
+                if self.paused[id] == 0 {
+                    self.head_or_wait(id, agent, rng);
+                    turned.push(id);
+                }
+                continue;
+            }
+            let mut steps_remaining = 1.0;
+            while let Some(remaining) = travel(agent, steps_remaining) {
+                steps_remaining = remaining;
+                push_turned(&mut turned, id);
+                // around the corner of an obstacle, on the way to the destination
+                if let Some(spot) = self.routes[id].pop() {
+                    agent.destination = spot;
+                    continue;
+                }
+                log::info!("Achieved {}", id);
+                if self.pause_time > 0 {
+                    self.paused[id] = rng.gen_range(0..=self.pause_time);
+                    if self.paused[id] > 0 {
+                        break;
+                    }
+                }
+                if !self.head_or_wait(id, agent, rng) {
+                    break;
+                }
+            }
+        }
+        turned
+    }
+}
+
+/// Random walk: a random direction and speed for `mobility_interval` steps,
+/// the legs that would leave the field are reflected off its borders
+pub struct RandomWalk {
+    field_size: f64,
+    speed_random: Uniform<f64>,
+    interval: u32,
+}
+
+impl RandomWalk {
+    pub fn new(params: &MovingModelParams) -> Self {
+        RandomWalk {
+            field_size: params.field_size,
+            speed_random: Uniform::new(params.min_velocity, params.max_velocity),
+            interval: params.mobility_interval,
+        }
+    }
+
+    fn head(&self, agent: &mut Agent, rng: &mut dyn RngCore) {
+        let angle = rng.gen_range(0.0..TAU);
+        agent.velocity = rng.sample(self.speed_random);
+        let length = agent.velocity * self.interval as f64;
+        agent.destination = point2(
+            reflect(agent.position.x + angle.cos() * length, self.field_size),
+            reflect(agent.position.y + angle.sin() * length, self.field_size),
+        );
+    }
+}
+
+impl Mobility for RandomWalk {
+    fn place(&mut self, count: u32, rng: &mut dyn RngCore) -> Vec<Agent> {
+        let field_random = Uniform::new(0.0, self.field_size);
+        std::iter::repeat_with(|| {
+            let position = point2(rng.sample(field_random), rng.sample(field_random));
+            let mut agent = Agent {
+                position,
+                destination: position,
+                velocity: 0.,
+            };
+            self.head(&mut agent, rng);
+            agent
+        })
+        .take(count as usize)
+        .collect()
+    }
+
+    fn step(&mut self, agents: &mut [Agent], rng: &mut dyn RngCore) -> Vec<usize> {
+        let mut turned = Vec::new();
+        for (id, agent) in agents.iter_mut().enumerate() {
+            let mut steps_remaining = 1.0;
+            while let Some(remaining) = travel(agent, steps_remaining) {
+                steps_remaining = remaining;
+                push_turned(&mut turned, id);
+                self.head(agent, rng);
+            }
+        }
+        turned
+    }
+}
+
+/// Random direction: a random direction and speed up to the border of the
+/// field, then a random pause of up to `pause_time` steps
+pub struct RandomDirection {
+    field_size: f64,
+    speed_random: Uniform<f64>,
+    pause_time: u32,
+    paused: Vec<u32>,
+}
+
+impl RandomDirection {
+    pub fn new(params: &MovingModelParams) -> Self {
+        RandomDirection {
+            field_size: params.field_size,
+            speed_random: Uniform::new(params.min_velocity, params.max_velocity),
+            pause_time: params.pause_time,
+            paused: Vec::new(),
+        }
+    }
+
+    fn head(&self, agent: &mut Agent, rng: &mut dyn RngCore) {
+        let size = self.field_size;
+        let angle = rng.gen_range(0.0..TAU);
+        let (mut dx, mut dy) = (angle.cos(), angle.sin());
+        let (x, y) = agent.position.to_tuple();
+        // at a border only the directions into the field make sense
+        if (dx > 0. && x >= size) || (dx < 0. && x <= 0.) {
+            dx = -dx;
+        }
+        if (dy > 0. && y >= size) || (dy < 0. && y <= 0.) {
+            dy = -dy;
+        }
+        // how far the border is along the direction
+        let along = |x: f64, dx: f64| {
+            if dx > 0. {
+                (size - x) / dx
+            } else if dx < 0. {
+                -x / dx
+            } else {
+                f64::INFINITY
+            }
+        };
+        let length = along(x, dx).min(along(y, dy));
+        agent.destination = point2(
+            (x + dx * length).clamp(0., size),
+            (y + dy * length).clamp(0., size),
+        );
+        agent.velocity = rng.sample(self.speed_random);
+    }
+}
+
+impl Mobility for RandomDirection {
+    fn place(&mut self, count: u32, rng: &mut dyn RngCore) -> Vec<Agent> {
+        self.paused = vec![0; count as usize];
+        let field_random = Uniform::new(0.0, self.field_size);
+        std::iter::repeat_with(|| {
+            let position = point2(rng.sample(field_random), rng.sample(field_random));
+            let mut agent = Agent {
+                position,
+                destination: position,
+                velocity: 0.,
+            };
+            self.head(&mut agent, rng);
+            agent
+        })
+        .take(count as usize)
+        .collect()
+    }
+
+    fn step(&mut self, agents: &mut [Agent], rng: &mut dyn RngCore) -> Vec<usize> {
+        let mut turned = Vec::new();
+        for (id, agent) in agents.iter_mut().enumerate() {
+            if self.paused[id] > 0 {
+                self.paused[id] -= 1;
+                if self.paused[id] == 0 {
+                    self.head(agent, rng);
+                    turned.push(id);
+                }
+                continue;
+            }
+            let mut steps_remaining = 1.0;
+            while let Some(remaining) = travel(agent, steps_remaining) {
+                steps_remaining = remaining;
+                push_turned(&mut turned, id);
+                if self.pause_time > 0 {
+                    self.paused[id] = rng.gen_range(0..=self.pause_time);
+                    if self.paused[id] > 0 {
+                        break;
+                    }
+                }
+                self.head(agent, rng);
+            }
+        }
+        turned
+    }
+}
+
+/// Gauss-Markov: every `mobility_interval` steps the speed and the direction
+/// are `gauss_markov_alpha` of the previous ones, the rest is their mean and
+/// a normal noise. Near the borders the mean direction turns to the middle
+pub struct GaussMarkov {
+    field_size: f64,
+    min_velocity: f64,
+    max_velocity: f64,
+    interval: u32,
+    alpha: f64,
+    // (speed, direction, mean direction) of every agent
+    state: Vec<(f64, f64, f64)>,
+}
+
+impl GaussMarkov {
+    pub fn new(params: &MovingModelParams) -> Self {
+        GaussMarkov {
+            field_size: params.field_size,
+            min_velocity: params.min_velocity,
+            max_velocity: params.max_velocity,
+            interval: params.mobility_interval,
+            alpha: params.gauss_markov_alpha,
+            state: Vec::new(),
+        }
+    }
+
+    fn head(&mut self, id: usize, agent: &mut Agent, rng: &mut dyn RngCore) {
+        let size = self.field_size;
+        let (speed, direction, mean) = &mut self.state[id];
+        let (x, y) = agent.position.to_tuple();
+        let margin = size * GAUSS_MARKOV_MARGIN;
+        let mean_direction = if x < margin || y < margin || x > size - margin || y > size - margin {
+            (size / 2. - y).atan2(size / 2. - x)
+        } else {
+            *mean
+        };
+        let mean_speed = (self.min_velocity + self.max_velocity) / 2.;
+        let memory = (1. - self.alpha * self.alpha).sqrt();
+        let noise: f64 = rng.sample(StandardNormal);
+        *speed = (self.alpha * *speed
+            + (1. - self.alpha) * mean_speed
+            + memory * noise * (self.max_velocity - self.min_velocity) / 2.)
+            .clamp(self.min_velocity, self.max_velocity);
+        let noise: f64 = rng.sample(StandardNormal);
+        *direction =
+            self.alpha * *direction + (1. - self.alpha) * mean_direction + memory * noise * PI / 4.;
+
+        agent.velocity = *speed;
+        let length = *speed * self.interval as f64;
+        agent.destination = point2(
+            reflect(x + direction.cos() * length, size),
+            reflect(y + direction.sin() * length, size),
+        );
+    }
+}
+
+impl Mobility for GaussMarkov {
+    fn place(&mut self, count: u32, rng: &mut dyn RngCore) -> Vec<Agent> {
+        let field_random = Uniform::new(0.0, self.field_size);
+        let speed_random = Uniform::new(self.min_velocity, self.max_velocity);
+        let mut agents = Vec::with_capacity(count as usize);
+        self.state.clear();
+        for id in 0..count as usize {
+            let position = point2(rng.sample(field_random), rng.sample(field_random));
+            let direction = rng.gen_range(0.0..TAU);
+            self.state
+                .push((rng.sample(speed_random), direction, direction));
+            let mut agent = Agent {
+                position,
+                destination: position,
+                velocity: 0.,
+            };
+            self.head(id, &mut agent, rng);
+            agents.push(agent);
+        }
+        agents
+    }
+
+    fn step(&mut self, agents: &mut [Agent], rng: &mut dyn RngCore) -> Vec<usize> {
+        let mut turned = Vec::new();
+        for (id, agent) in agents.iter_mut().enumerate() {
+            let mut steps_remaining = 1.0;
+            while let Some(remaining) = travel(agent, steps_remaining) {
+                steps_remaining = remaining;
+                push_turned(&mut turned, id);
+                self.head(id, agent, rng);
+            }
+        }
+        turned
+    }
+}
+
+/// Manhattan grid: `streets` streets each way split the field in blocks, the
+/// agents go a block at a time at a random speed. At a crossing they go on
+/// straight with a probability of 0.5 and turn left or right with 0.25 each
+pub struct Manhattan {
+    field_size: f64,
+    speed_random: Uniform<f64>,
+    blocks: u32,
+    // (crossing, heading) of every agent, the crossing it heads to
+    state: Vec<((u32, u32), (i32, i32))>,
+}
+
+impl Manhattan {
+    pub fn new(params: &MovingModelParams) -> Self {
+        Manhattan {
+            field_size: params.field_size,
+            speed_random: Uniform::new(params.min_velocity, params.max_velocity),
+            blocks: params.streets.max(2) - 1,
+            state: Vec::new(),
+        }
+    }
+
+    fn crossing(&self, (x, y): (u32, u32)) -> Point2D<f64, UnknownUnit> {
+        let block = self.field_size / self.blocks as f64;
+        point2(x as f64 * block, y as f64 * block)
+    }
+
+    fn head(&mut self, id: usize, agent: &mut Agent, rng: &mut dyn RngCore) {
+        let ((x, y), (dx, dy)) = self.state[id];
+        let inside = |(dx, dy): (i32, i32)| {
+            let (x, y) = (x as i64 + dx as i64, y as i64 + dy as i64);
+            (0..=self.blocks as i64).contains(&x) && (0..=self.blocks as i64).contains(&y)
+        };
+        // straight, left and right
+        let options = [(dx, dy), (dy, -dx), (-dy, dx)];
+        let weights = [2, 1, 1];
+        let allowed: Vec<usize> = (0..options.len()).filter(|&i| inside(options[i])).collect();
+        let heading = if allowed.is_empty() {
+            // a dead end, back the same way
+            (-dx, -dy)
+        } else {
+            let total: u32 = allowed.iter().map(|&i| weights[i]).sum();
+            let mut pick = rng.gen_range(0..total);
+            let mut chosen = allowed[0];
+            for &i in allowed.iter() {
+                if pick < weights[i] {
+                    chosen = i;
+                    break;
+                }
+                pick -= weights[i];
+            }
+            options[chosen]
+        };
+        let next = ((x as i32 + heading.0) as u32, (y as i32 + heading.1) as u32);
+        self.state[id] = (next, heading);
+        agent.destination = self.crossing(next);
+        agent.velocity = rng.sample(self.speed_random);
+    }
+}
+
+impl Mobility for Manhattan {
+    fn place(&mut self, count: u32, rng: &mut dyn RngCore) -> Vec<Agent> {
+        const HEADINGS: [(i32, i32); 4] = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+        let mut agents = Vec::with_capacity(count as usize);
+        self.state.clear();
+        for id in 0..count as usize {
+            let crossing = (
+                rng.gen_range(0..=self.blocks),
+                rng.gen_range(0..=self.blocks),
+            );
+            self.state
+                .push((crossing, HEADINGS[rng.gen_range(0..HEADINGS.len())]));
+            let position = self.crossing(crossing);
+            let mut agent = Agent {
+                position,
+                destination: position,
+                velocity: 0.,
+            };
+            self.head(id, &mut agent, rng);
+            agents.push(agent);
+        }
+        agents
+    }
+
+    fn step(&mut self, agents: &mut [Agent], rng: &mut dyn RngCore) -> Vec<usize> {
+        let mut turned = Vec::new();
+        for (id, agent) in agents.iter_mut().enumerate() {
+            let mut steps_remaining = 1.0;
+            while let Some(remaining) = travel(agent, steps_remaining) {
+                steps_remaining = remaining;
+                push_turned(&mut turned, id);
+                self.head(id, agent, rng);
+            }
+        }
+        turned
+    }
+}
+
+/// Reference point group mobility: the agents are split in groups of
+/// `group_size`, a reference point of every group moves like a random
+/// waypoint. The members head to spots within `group_radius` of its
+/// destination, at the speeds that get them there along with it, and wait
+/// while it pauses
+pub struct Group {
+    field_size: f64,
+    group_size: u32,
+    radius: f64,
+    references: Vec<Agent>,
+    waypoint: RandomWaypoint,
+}
+
+impl Group {
+    pub fn new(params: &MovingModelParams) -> Self {
+        Group {
+            field_size: params.field_size,
+            group_size: params.group_size.max(1),
+            radius: params.group_radius,
+            references: Vec::new(),
+            waypoint: RandomWaypoint::new(params),
+        }
+    }
+
+    // a random spot within the radius around `center`, inside the field
+    fn around(
+        &self,
+        center: Point2D<f64, UnknownUnit>,
+        rng: &mut dyn RngCore,
+    ) -> Point2D<f64, UnknownUnit> {
+        let angle = rng.gen_range(0.0..TAU);
+        // uniform over the disk, not crowded in the middle
+        let distance = self.radius * rng.gen::<f64>().sqrt();
+        point2(
+            (center.x + angle.cos() * distance).clamp(0., self.field_size),
+            (center.y + angle.sin() * distance).clamp(0., self.field_size),
+        )
+    }
+
+    // sends the members of the group after their reference point, or stops
+    // them while it pauses
+    fn follow(&self, group: usize, agents: &mut [Agent], rng: &mut dyn RngCore) {
+        let reference = &self.references[group];
+        let paused = self.waypoint.paused[group] > 0;
+        // steps the reference point takes to the end of its leg
+        let time = reference.destination.distance_to(reference.position) / reference.velocity;
+        let first = group * self.group_size as usize;
+        let last = (first + self.group_size as usize).min(agents.len());
+        for agent in agents[first..last].iter_mut() {
+            if paused {
+                agent.destination = agent.position;
+                agent.velocity = 0.;
+            } else {
+                agent.destination = self.around(reference.destination, rng);
+                agent.velocity = agent.destination.distance_to(agent.position) / time.max(1.);
+            }
+        }
+    }
+}
+
+impl Mobility for Group {
+    fn place(&mut self, count: u32, rng: &mut dyn RngCore) -> Vec<Agent> {
+        let groups = count.div_ceil(self.group_size);
+        self.references = self.waypoint.place(groups, rng);
+        let mut agents: Vec<Agent> = (0..count as usize)
+            .map(|id| {
+                let reference = self.references[id / self.group_size as usize];
+                let position = self.around(reference.position, rng);
+                Agent {
+                    position,
+                    destination: position,
+                    velocity: 0.,
+                }
+            })
+            .collect();
+        for group in 0..groups as usize {
+            self.follow(group, &mut agents, rng);
+        }
+        agents
+    }
+
+    fn step(&mut self, agents: &mut [Agent], rng: &mut dyn RngCore) -> Vec<usize> {
+        let mut references = std::mem::take(&mut self.references);
+        let groups = self.waypoint.step(&mut references, rng);
+        self.references = references;
+
+        for agent in agents.iter_mut() {
+            // the ones that got there first wait for the rest of the group
+            if agent.position != agent.destination {
+                travel(agent, 1.0);
+            }
+        }
+        let mut turned = Vec::new();
+        for group in groups {
+            self.follow(group, agents, rng);
+            let first = group * self.group_size as usize;
+            turned.extend(first..(first + self.group_size as usize).min(agents.len()));
+        }
+        turned
+    }
+}
+
+#[test]
+fn models() {
+    let _ = env_logger::builder().try_init();
+
+    let base = MovingModelParams {
+        agents_count: 20,
+        min_velocity: 0.01,
+        max_velocity: 0.05,
+        pause_time: 500,
+        mobility_interval: 1000,
+        streets: 5,
+        group_size: 4,
+        group_radius: 10.,
+        ..Default::default()
+    };
+    let size = base.field_size;
+    for model in MobilityModel::value_variants() {
+        let params = MovingModelParams {
+            mobility: *model,
+            ..base.clone()
+        };
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut mobility = mobility(&params).unwrap();
+        let mut agents = mobility.place(params.agents_count, &mut rng);
+        let start: Vec<_> = agents.iter().map(|agent| agent.position).collect();
+        let mut turns = 0;
+        let mut stopped = 0;
+        for _ in 0..20000 {
+            let before: Vec<_> = agents.iter().map(|agent| agent.position).collect();
+            turns += mobility.step(&mut agents, &mut rng).len();
+            for (agent, before) in agents.iter().zip(before) {
+                let (x, y) = agent.position.to_tuple();
+                assert!((0. ..=size).contains(&x) && (0. ..=size).contains(&y));
+                assert!((0. ..=size).contains(&agent.destination.x));
+                assert!((0. ..=size).contains(&agent.destination.y));
+                if agent.position == before {
+                    stopped += 1;
+                }
+                if *model == MobilityModel::Manhattan {
+                    // always on a street
+                    let block = size / 4.;
+                    let on = |x: f64| ((x / block).round() * block - x).abs() < 1e-6;
+                    assert!(on(x) || on(y), "{:?} off the streets", (x, y));
+                }
+            }
+        }
+        log::info!("{:?}: {} turns, {} stopped", model, turns, stopped);
+        assert!(turns > 0, "{:?} never turned", model);
+        assert!(agents
+            .iter()
+            .zip(start)
+            .any(|(agent, start)| agent.position != start));
+        // only the ones with pauses stop on the way
+        let pauses = matches!(
+            model,
+            MobilityModel::Waypoint | MobilityModel::Direction | MobilityModel::Group
+        );
+        assert_eq!(stopped > 0, pauses, "{:?}", model);
+
+        if *model == MobilityModel::Group {
+            // members stay close to each other
+            for group in agents.chunks(params.group_size as usize) {
+                for a in group {
+                    for b in group {
+                        assert!(a.position.distance_to(b.position) <= 4. * params.group_radius);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn around_obstacles() {
+    let _ = env_logger::builder().try_init();
+
+    // a building in the middle and a wall across most of the field
+    let params = MovingModelParams {
+        agents_count: 20,
+        min_velocity: 0.01,
+        max_velocity: 0.05,
+        obstacles: vec![
+            "30,30,60,30,60,60,30,60:10".parse().unwrap(),
+            "80,0,80,90".parse().unwrap(),
+        ],
+        ..Default::default()
+    };
+    let obstacles = Obstacles::new(&params.obstacles, params.field_size);
+    let mut rng = StdRng::seed_from_u64(1);
+    let mut mobility = mobility(&params).unwrap();
+    let mut agents = mobility.place(params.agents_count, &mut rng);
+    let mut crossed_wall = 0;
+    for _ in 0..50000 {
+        let before: Vec<_> = agents.iter().map(|agent| agent.position).collect();
+        mobility.step(&mut agents, &mut rng);
+        for (agent, before) in agents.iter().zip(before) {
+            assert!(!obstacles.inside(agent.position));
+            assert!(obstacles.clear(before, agent.position));
+            if (before.x < 80.) != (agent.position.x < 80.) {
+                crossed_wall += 1;
+            }
+        }
+    }
+    // over the wall, around its end
+    assert!(crossed_wall > 0);
+}
+
+#[test]
+fn shut_in() {
+    let _ = env_logger::builder().try_init();
+
+    // a room around the whole field, nowhere to go
+    let params = MovingModelParams {
+        agents_count: 5,
+        obstacles: vec!["0,0,100,0,100,100,0,100:10".parse().unwrap()],
+        ..Default::default()
+    };
+    let mut rng = StdRng::seed_from_u64(1);
+    let mut mobility = mobility(&params).unwrap();
+    let mut agents = mobility.place(params.agents_count, &mut rng);
+    let placed: Vec<_> = agents.iter().map(|agent| agent.position).collect();
+    for _ in 0..6000 {
+        mobility.step(&mut agents, &mut rng);
+    }
+    for (agent, placed) in agents.iter().zip(placed) {
+        assert_eq!(agent.position, placed);
+    }
+}
@@ -1,11 +1,11 @@
 pub mod common;
 pub mod complete;
-pub mod sparse;
+pub mod experiment;
+pub mod mobility;
+pub mod movements;
 pub mod moving;
+pub mod obstacles;
+pub mod sparse;
+pub mod sweep;
 
 pub use common::*;
-pub use complete::*;
-pub use sparse::*;
-pub use moving::*;
-
-
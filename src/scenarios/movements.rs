@@ -0,0 +1,317 @@
+use std::io::BufRead;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context};
+use euclid::*;
+use rand::RngCore;
+
+use crate::scenarios::mobility::*;
+
+// the files count in seconds and meters, the model in steps and meters
+const STEPS_PER_SECOND: f64 = 1000.;
+
+type Point = Point2D<f64, UnknownUnit>;
+
+/// Recorded movement of every node: the spots it passes at the given steps,
+/// it goes in a straight line between them and stays at the first and the
+/// last one before and after them
+#[derive(Debug, Clone, Default)]
+pub struct Movements {
+    pub paths: Vec<Vec<(f64 /* step */, Point)>>,
+}
+
+impl Movements {
+    /// Reads an ns-2 setdest or a BonnMotion movements file, whichever it
+    /// looks like
+    pub fn load(path: impl AsRef<Path>) -> Result<Movements, anyhow::Error> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| anyhow!("can't open {}: {}", path.display(), err))?;
+        let movements = if text.contains("$node_(") {
+            Movements::parse_ns2(text.as_bytes())
+        } else {
+            Movements::parse_bonnmotion(text.as_bytes())
+        };
+        movements.with_context(|| format!("in {}", path.display()))
+    }
+
+    /// ns-2 movement as written by setdest: the starting spots set with
+    /// `$node_(i) set X_ x` (and `Y_`) and the moves scheduled with
+    /// `$ns_ at t "$node_(i) setdest x y speed"`, everything else is skipped.
+    /// A move cuts short the one the node is making at the time
+    pub fn parse_ns2(reader: impl BufRead) -> Result<Movements, anyhow::Error> {
+        let mut starts: Vec<(Option<f64>, Option<f64>)> = Vec::new();
+        // (step, node, destination, distance per step)
+        let mut moves: Vec<(f64, usize, Point, f64)> = Vec::new();
+        for (number, line) in reader.lines().enumerate() {
+            let line = line?;
+            let tokens: Vec<&str> = line
+                .split_whitespace()
+                .map(|token| token.trim_matches('"'))
+                .filter(|token| !token.is_empty())
+                .collect();
+            let parse = |token: Option<&&str>| -> Result<f64, anyhow::Error> {
+                let token = token.ok_or_else(|| anyhow!("line {} is cut short", number + 1))?;
+                token
+                    .parse()
+                    .map_err(|_| anyhow!("bad number {:?} at line {}", token, number + 1))
+            };
+            match tokens.as_slice() {
+                [node, "set", axis, value, ..] if node.starts_with("$node_(") => {
+                    let node = ns2_node(node, number)?;
+                    if starts.len() <= node {
+                        starts.resize(node + 1, (None, None));
+                    }
+                    match *axis {
+                        "X_" => starts[node].0 = Some(parse(Some(value))?),
+                        "Y_" => starts[node].1 = Some(parse(Some(value))?),
+                        _ => {}
+                    }
+                }
+                ["$ns_", "at", time, node, "setdest", rest @ ..] if node.starts_with("$node_(") => {
+                    let node = ns2_node(node, number)?;
+                    let destination = point2(parse(rest.first())?, parse(rest.get(1))?);
+                    let speed = parse(rest.get(2))?;
+                    moves.push((
+                        parse(Some(time))? * STEPS_PER_SECOND,
+                        node,
+                        destination,
+                        speed / STEPS_PER_SECOND,
+                    ));
+                }
+                _ => {}
+            }
+        }
+
+        let nodes = moves
+            .iter()
+            .map(|&(_, node, ..)| node + 1)
+            .max()
+            .unwrap_or(0)
+            .max(starts.len());
+        starts.resize(nodes, (None, None));
+        let mut movements = Movements {
+            paths: Vec::with_capacity(nodes),
+        };
+        for (node, start) in starts.into_iter().enumerate() {
+            let start = match start {
+                (Some(x), Some(y)) => point2(x, y),
+                _ => bail!("node {} has no starting position", node),
+            };
+            movements.paths.push(vec![(0., start)]);
+        }
+        // the file doesn't have to be in order of time
+        moves.sort_by(|a, b| a.0.total_cmp(&b.0));
+        for (step, node, destination, speed) in moves {
+            let position = movements.position(node, step);
+            let path = &mut movements.paths[node];
+            path.retain(|&(at, _)| at < step);
+            path.push((step, position));
+            if speed > 0. {
+                path.push((
+                    step + destination.distance_to(position) / speed,
+                    destination,
+                ));
+            }
+        }
+        Ok(movements)
+    }
+
+    /// BonnMotion movements: a line for every node with `time x y` for every
+    /// spot it passes
+    pub fn parse_bonnmotion(reader: impl BufRead) -> Result<Movements, anyhow::Error> {
+        let mut movements = Movements::default();
+        for (number, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let values = line
+                .split_whitespace()
+                .map(|value| {
+                    value
+                        .parse::<f64>()
+                        .map_err(|_| anyhow!("bad number {:?} at line {}", value, number + 1))
+                })
+                .collect::<Result<Vec<f64>, _>>()?;
+            if values.len() % 3 != 0 {
+                bail!("line {} isn't made of time x y triples", number + 1);
+            }
+            let path: Vec<(f64, Point)> = values
+                .chunks(3)
+                .map(|spot| (spot[0] * STEPS_PER_SECOND, point2(spot[1], spot[2])))
+                .collect();
+            if path.windows(2).any(|pair| pair[1].0 < pair[0].0) {
+                bail!("times go back at line {}", number + 1);
+            }
+            movements.paths.push(path);
+        }
+        Ok(movements)
+    }
+
+    pub fn nodes(&self) -> u32 {
+        self.paths.len() as u32
+    }
+
+    /// Where the node is at `step`
+    pub fn position(&self, node: usize, step: f64) -> Point {
+        let path = &self.paths[node];
+        let next = path.partition_point(|&(at, _)| at <= step);
+        match (next.checked_sub(1).map(|last| path[last]), path.get(next)) {
+            (Some((from, a)), Some(&(to, b))) => a.lerp(b, (step - from) / (to - from)),
+            (Some((_, a)), None) => a,
+            (None, Some(&(_, b))) => b,
+            (None, None) => point2(0., 0.),
+        }
+    }
+
+    /// Furthest the nodes get from (0, 0) along any of the axes
+    pub fn extent(&self) -> f64 {
+        self.paths
+            .iter()
+            .flatten()
+            .map(|(_, spot)| spot.x.abs().max(spot.y.abs()))
+            .fold(0., f64::max)
+    }
+}
+
+// the i of `$node_(i)`
+fn ns2_node(token: &str, number: usize) -> Result<usize, anyhow::Error> {
+    token
+        .strip_prefix("$node_(")
+        .and_then(|rest| rest.strip_suffix(')'))
+        .and_then(|node| node.parse().ok())
+        .ok_or_else(|| anyhow!("bad node {:?} at line {}", token, number + 1))
+}
+
+/// The agents follow recorded movements instead of a model, the recording
+/// starts when they start to move
+pub struct Recorded {
+    movements: Movements,
+    // steps since the start of the recording
+    clock: u32,
+    // the spot of the path every agent heads to
+    next: Vec<usize>,
+}
+
+impl Recorded {
+    pub fn new(movements: Movements) -> Self {
+        Recorded {
+            movements,
+            clock: 0,
+            next: Vec::new(),
+        }
+    }
+
+    // where the agent heads to and how fast, a pause or the end of its path
+    // keep it in place
+    fn head(&self, id: usize, agent: &mut Agent) {
+        let path = &self.movements.paths[id];
+        match path.get(self.next[id]) {
+            Some(&(at, spot)) if self.next[id] > 0 => {
+                agent.destination = spot;
+                let from = path[self.next[id] - 1].0.max(self.clock as f64);
+                agent.velocity = spot.distance_to(agent.position) / (at - from).max(1e-9);
+            }
+            _ => {
+                agent.destination = agent.position;
+                agent.velocity = 0.;
+            }
+        }
+    }
+}
+
+impl Mobility for Recorded {
+    fn place(&mut self, count: u32, _rng: &mut dyn RngCore) -> Vec<Agent> {
+        self.next = (0..count as usize)
+            .map(|id| self.movements.paths[id].partition_point(|&(at, _)| at <= 0.))
+            .collect();
+        (0..count as usize)
+            .map(|id| {
+                let mut agent = Agent {
+                    position: self.movements.position(id, 0.),
+                    destination: point2(0., 0.),
+                    velocity: 0.,
+                };
+                self.head(id, &mut agent);
+                agent
+            })
+            .collect()
+    }
+
+    fn step(&mut self, agents: &mut [Agent], _rng: &mut dyn RngCore) -> Vec<usize> {
+        self.clock += 1;
+        let step = self.clock as f64;
+        let mut turned = Vec::new();
+        for (id, agent) in agents.iter_mut().enumerate() {
+            agent.position = self.movements.position(id, step);
+            let next = self.movements.paths[id].partition_point(|&(at, _)| at <= step);
+            if next != self.next[id] {
+                self.next[id] = next;
+                self.head(id, agent);
+                turned.push(id);
+            }
+        }
+        turned
+    }
+}
+
+#[test]
+fn formats() {
+    let _ = env_logger::builder().try_init();
+
+    let ns2 = r#"
+# nodes: 2, max time: 10.00
+$node_(0) set X_ 10.0
+$node_(0) set Y_ 20.0
+$node_(0) set Z_ 0.0
+$node_(1) set X_ 0.0
+$node_(1) set Y_ 0.0
+$node_(1) set Z_ 0.0
+$god_ set-dist 0 1 1
+$ns_ at 2.0 "$node_(1) setdest 30.0 40.0 10.0"
+$ns_ at 1.0 "$node_(0) setdest 10.0 40.0 2.0"
+$ns_ at 6.0 "$node_(0) setdest 0.0 30.0 1.0"
+$ns_ at 3.0 "$god_ set-dist 0 1 2"
+"#;
+    let movements = Movements::parse_ns2(ns2.as_bytes()).unwrap();
+    assert_eq!(movements.nodes(), 2);
+    // waits, then 5 seconds for 50 meters
+    assert_eq!(movements.position(1, 1000.), point2(0., 0.));
+    assert_eq!(movements.position(1, 4500.), point2(15., 20.));
+    assert_eq!(movements.position(1, 20000.), point2(30., 40.));
+    // cut short at 30 after 10 of the 20 meters, then off to (0, 30)
+    assert_eq!(movements.position(0, 6000.), point2(10., 30.));
+    assert_eq!(movements.position(0, 11000.), point2(5., 30.));
+    assert_eq!(movements.position(0, 16000.), point2(0., 30.));
+    assert!(Movements::parse_ns2(r#"$ns_ at 1.0 "$node_(0) setdest 1 2""#.as_bytes()).is_err());
+    assert!(Movements::parse_ns2(r#"$node_(0) set X_ 1.0"#.as_bytes()).is_err());
+
+    let bonnmotion = "0.0 0.0 0.0 10.0 100.0 0.0 20.0 100.0 0.0\n5.0 50.0 50.0\n";
+    let movements = Movements::parse_bonnmotion(bonnmotion.as_bytes()).unwrap();
+    assert_eq!(movements.nodes(), 2);
+    assert_eq!(movements.position(0, 2500.), point2(25., 0.));
+    assert_eq!(movements.position(0, 15000.), point2(100., 0.));
+    assert_eq!(movements.position(1, 0.), point2(50., 50.));
+    assert_eq!(movements.extent(), 100.);
+    assert!(Movements::parse_bonnmotion("0.0 1.0\n".as_bytes()).is_err());
+    assert!(Movements::parse_bonnmotion("5.0 1.0 1.0 1.0 2.0 2.0\n".as_bytes()).is_err());
+
+    // the agents follow the recording step by step
+    let mut recorded = Recorded::new(movements);
+    let mut rng = rand::thread_rng();
+    let mut agents = recorded.place(2, &mut rng);
+    assert_eq!(agents[0].destination, point2(100., 0.));
+    assert_eq!(agents[0].velocity, 0.01);
+    assert_eq!(agents[1].velocity, 0.);
+    let mut turns = Vec::new();
+    for step in 1..=20000 {
+        for id in recorded.step(&mut agents, &mut rng) {
+            turns.push((step, id));
+        }
+    }
+    // the second one only shows up at its first spot
+    assert_eq!(turns, [(5000, 1), (10000, 0), (20000, 0)]);
+    assert_eq!(agents[0].position, point2(100., 0.));
+    assert_eq!(agents[0].velocity, 0.);
+}
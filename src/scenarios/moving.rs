@@ -1,11 +1,16 @@
 use rand::prelude::*;
 
-use envmnt::get_parse_or;
-
 use crate::model::*;
+#[cfg(test)]
+use crate::protocols::*;
+use crate::scenarios::mobility::*;
+use crate::scenarios::obstacles::*;
 use crate::scenarios::*;
 
-use euclid::*;
+use clap::{Args, ValueEnum};
+use rand_distr::Exp;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
 
 const DEFAULT_STEPS_COUNT: u32 = 1000 * 60 * 10; /* 10 minutes */
 const DEFAULT_AGENTS_COUNT: u32 = 10;
@@ -15,177 +20,859 @@ const DEFAULT_MIN_VELOCITY: f64 = 0.001 / 2.0; /* 0.5 m/sec */
 const DEFAULT_MAX_VELOCITY: f64 = 0.001 * 2.0; /* 2.0 m/sec */
 const DEFAULT_MAX_CONNECTION_RANGE: f64 = 30.; /* 30 m */
 const DEFAULT_STARTUP_AWAIT: u32 = 5 * 1000; /* 5 seconds for a startup */
+const DEFAULT_CSMA_WINDOW: u32 = 0; /* no carrier sense */
+const CSMA_MAX_ATTEMPTS: u32 = 5;
+const DEFAULT_BATTERY: f64 = 0.; /* no energy model */
+const DEFAULT_TRANSMIT_COST: f64 = 0.002; /* per byte */
+const DEFAULT_RECEIVE_COST: f64 = 0.001; /* per byte */
+const DEFAULT_IDLE_COST: f64 = 0.0001; /* per step */
+const DEFAULT_SLEEP_COST: f64 = 0.00001; /* per step */
+const DEFAULT_MEAN_UPTIME: f64 = 0.; /* no churn */
+const DEFAULT_MEAN_DOWNTIME: f64 = 10. * 1000.; /* 10 seconds */
+const DEFAULT_PAUSE_TIME: u32 = 0; /* no pauses */
+const DEFAULT_MOBILITY_INTERVAL: u32 = 10 * 1000; /* 10 seconds */
+const DEFAULT_GAUSS_MARKOV_ALPHA: f64 = 0.75;
+const DEFAULT_STREETS: u32 = 5;
+const DEFAULT_GROUP_SIZE: u32 = 5;
+const DEFAULT_GROUP_RADIUS: f64 = 10.; /* 10 m */
+const DEFAULT_TRANSMIT_POWER: f64 = 0.; /* 1 mW */
+const DEFAULT_SENSITIVITY: f64 = -70.; /* about 30 m in free space */
+const DEFAULT_FREQUENCY: f64 = 2.4e9; /* 2.4 GHz */
+const DEFAULT_PATH_LOSS_EXPONENT: f64 = 3.;
+const DEFAULT_REFERENCE_DISTANCE: f64 = 1.; /* 1 m */
+const DEFAULT_ANTENNA_HEIGHT: f64 = 1.5; /* 1.5 m */
+const DEFAULT_SHADOWING: f64 = 0.; /* no shadowing */
+const DEFAULT_BURST_LENGTH: f64 = 0.; /* independent losses */
+const DEFAULT_BURST_GAP: f64 = 1000.; /* a second */
+const DEFAULT_BURST_LOSS: f64 = 1.;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum PropagationModel {
+    /// Every agent within max_connection_range hears every message
+    Disk,
+    /// Friis free space path loss
+    FreeSpace,
+    /// Free space up to the crossover distance, then the ground reflection
+    TwoRay,
+    /// Path loss growing with the distance to the power of
+    /// path_loss_exponent
+    LogDistance,
+}
 
-#[derive(Debug)]
-struct MovingModelParams {
-    steps_count: u32,
-    agents_count: u32,
-    messages_count: u32,
-    field_size: f64,
-    min_velocity: f64,
-    max_velocity: f64,
-    max_connection_range: f64,
-    startup_await: u32,
-    seed: Option<u64>,
+/// What an agent hears and is heard with
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Radio {
+    /// Distance the others hear the agent at in the disk model, in meters
+    pub range: f64,
+    /// Transmit power with the antenna gains, in dBm
+    pub transmit_power: f64,
+    /// Weakest signal still received, in dBm
+    pub sensitivity: f64,
 }
 
-#[derive(Copy, Clone)]
-struct Agent {
-    position: Point2D<f64, UnknownUnit>,
-    destination: Point2D<f64, UnknownUnit>,
-    velocity: f64, // Distance traversed by one person in 1 step
+/// Radio of a part of the agents, `share:range:transmit_power:sensitivity`
+/// on the command line
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RadioProfile {
+    pub share: f64,
+    #[serde(flatten)]
+    pub radio: Radio,
 }
 
-fn update_connections_via_positions<T: Clone + core::fmt::Debug, R>(
-    model: &mut Model<T, R>,
+impl std::str::FromStr for RadioProfile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split(':')
+            .map(|value| {
+                value
+                    .parse::<f64>()
+                    .map_err(|err| format!("{}: {}", value, err))
+            })
+            .collect::<Result<Vec<f64>, _>>()?;
+        match values.as_slice() {
+            &[share, range, transmit_power, sensitivity] => Ok(RadioProfile {
+                share,
+                radio: Radio {
+                    range,
+                    transmit_power,
+                    sensitivity,
+                },
+            }),
+            _ => Err("expected share:range:transmit_power:sensitivity".to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Args)]
+#[serde(default)]
+pub struct MovingModelParams {
+    /// Steps to run for at most, a step is a millisecond
+    #[arg(long, default_value_t = DEFAULT_STEPS_COUNT)]
+    pub steps_count: u32,
+    #[arg(long, default_value_t = DEFAULT_AGENTS_COUNT, value_parser = clap::value_parser!(u32).range(2..))]
+    pub agents_count: u32,
+    /// Messages requested between random agents after the startup
+    #[arg(long, default_value_t = DEFAULT_MESSAGES_COUNT)]
+    pub messages_count: u32,
+    /// Side of the square field, in meters
+    #[arg(long, default_value_t = DEFAULT_FIELD_SIZE)]
+    pub field_size: f64,
+    /// Speed of the agents, in meters per step
+    #[arg(long, default_value_t = DEFAULT_MIN_VELOCITY)]
+    pub min_velocity: f64,
+    #[arg(long, default_value_t = DEFAULT_MAX_VELOCITY)]
+    pub max_velocity: f64,
+    /// Agents further apart than that don't hear each other, in meters
+    #[arg(long, default_value_t = DEFAULT_MAX_CONNECTION_RANGE)]
+    pub max_connection_range: f64,
+    /// Steps the protocols get to settle before the messages are requested
+    #[arg(long, default_value_t = DEFAULT_STARTUP_AWAIT)]
+    pub startup_await: u32,
+    #[arg(long)]
+    pub seed: Option<u64>,
+    /// Jump over the steps where nothing happens
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    pub event_driven: bool,
+    /// Let overlapping receptions collide
+    #[arg(long)]
+    pub mac: bool,
+    /// Initial CSMA contention window in steps, 0 turns carrier sense off
+    #[arg(long, default_value_t = DEFAULT_CSMA_WINDOW)]
+    pub csma_window: u32,
+    /// Battery of every agent, 0 turns the energy model off
+    #[arg(long, default_value_t = DEFAULT_BATTERY)]
+    pub battery: f64,
+    /// Energy per byte sent
+    #[arg(long, default_value_t = DEFAULT_TRANSMIT_COST)]
+    pub transmit_cost: f64,
+    /// Energy per byte received
+    #[arg(long, default_value_t = DEFAULT_RECEIVE_COST)]
+    pub receive_cost: f64,
+    /// Energy per step with the radio on
+    #[arg(long, default_value_t = DEFAULT_IDLE_COST)]
+    pub idle_cost: f64,
+    /// Energy per step asleep
+    #[arg(long, default_value_t = DEFAULT_SLEEP_COST)]
+    pub sleep_cost: f64,
+    /// Mean steps between an agent's restart and its next crash, 0 turns
+    /// churn off
+    #[arg(long, default_value_t = DEFAULT_MEAN_UPTIME)]
+    pub mean_uptime: f64,
+    /// Mean steps an agent stays down after a crash
+    #[arg(long, default_value_t = DEFAULT_MEAN_DOWNTIME)]
+    pub mean_downtime: f64,
+    /// Restarted agents keep their state instead of starting from scratch
+    #[arg(long)]
+    pub churn_keep_state: bool,
+    /// How the agents move around the field
+    #[arg(long, value_enum, default_value_t = MobilityModel::Waypoint)]
+    pub mobility: MobilityModel,
+    /// Longest pause at a destination of the waypoint, direction and group
+    /// models, in steps
+    #[arg(long, default_value_t = DEFAULT_PAUSE_TIME)]
+    pub pause_time: u32,
+    /// Steps between the course changes of the walk and Gauss-Markov models
+    #[arg(long, default_value_t = DEFAULT_MOBILITY_INTERVAL, value_parser = clap::value_parser!(u32).range(1..))]
+    pub mobility_interval: u32,
+    /// Part of the previous speed and direction kept by the Gauss-Markov
+    /// model, 0 is a random walk and 1 a straight line
+    #[arg(long, default_value_t = DEFAULT_GAUSS_MARKOV_ALPHA)]
+    pub gauss_markov_alpha: f64,
+    /// Streets each way across the field of the Manhattan model
+    #[arg(long, default_value_t = DEFAULT_STREETS, value_parser = clap::value_parser!(u32).range(2..))]
+    pub streets: u32,
+    /// Agents moving together in the group model
+    #[arg(long, default_value_t = DEFAULT_GROUP_SIZE, value_parser = clap::value_parser!(u32).range(1..))]
+    pub group_size: u32,
+    /// How far the group members are from their reference point, in meters
+    #[arg(long, default_value_t = DEFAULT_GROUP_RADIUS)]
+    pub group_radius: f64,
+    /// ns-2 setdest or BonnMotion movements file for the agents to follow
+    /// instead of the mobility model, in seconds and meters. Its time 0 is
+    /// the end of the startup
+    #[arg(long)]
+    pub movements: Option<PathBuf>,
+    /// How the signal fades with the distance, the models other than disk
+    /// turn the transmit power and the sensitivity into the range
+    #[arg(long, value_enum, default_value_t = PropagationModel::Disk)]
+    pub propagation: PropagationModel,
+    /// Transmit power with the antenna gains, in dBm
+    #[arg(long, default_value_t = DEFAULT_TRANSMIT_POWER, allow_negative_numbers = true)]
+    pub transmit_power: f64,
+    /// Weakest signal still received, in dBm
+    #[arg(long, default_value_t = DEFAULT_SENSITIVITY, allow_negative_numbers = true)]
+    pub sensitivity: f64,
+    /// Carrier frequency, in Hz
+    #[arg(long, default_value_t = DEFAULT_FREQUENCY)]
+    pub frequency: f64,
+    /// How fast the signal fades in the log-distance model, 2 is free space
+    #[arg(long, default_value_t = DEFAULT_PATH_LOSS_EXPONENT)]
+    pub path_loss_exponent: f64,
+    /// Distance the log-distance model starts from, free space up to it, in
+    /// meters
+    #[arg(long, default_value_t = DEFAULT_REFERENCE_DISTANCE)]
+    pub reference_distance: f64,
+    /// Height of the antennas above the ground in the two-ray model, in meters
+    #[arg(long, default_value_t = DEFAULT_ANTENNA_HEIGHT)]
+    pub antenna_height: f64,
+    /// Deviation of the log-normal shadowing, in dB, 0 turns it off
+    #[arg(long, default_value_t = DEFAULT_SHADOWING)]
+    pub shadowing: f64,
+    /// Radio of a share of the agents, like 0.2:100:20:-90 for a fifth of
+    /// them reaching 100 meters with 20 dBm and hearing down to -90 dBm. The
+    /// first agents get the first profile, the ones left over the range,
+    /// transmit power and sensitivity above
+    #[arg(
+        long = "radio",
+        value_name = "SHARE:RANGE:TRANSMIT_POWER:SENSITIVITY",
+        allow_negative_numbers = true
+    )]
+    pub radios: Vec<RadioProfile>,
+    /// Building or wall, like 20,20,40,20,40,40,20,40:12 for a square
+    /// losing 12 dB or 60,0,60,50 for a wall blocking the signal. The
+    /// waypoint agents go around them
+    #[arg(long = "obstacle", value_name = "X,Y,X,Y,...[:ATTENUATION]")]
+    pub obstacles: Vec<Obstacle>,
+    /// Mean steps a link stays bad in a burst of losses, 0 loses messages
+    /// independently
+    #[arg(long, default_value_t = DEFAULT_BURST_LENGTH)]
+    pub burst_length: f64,
+    /// Mean steps between the bursts
+    #[arg(long, default_value_t = DEFAULT_BURST_GAP)]
+    pub burst_gap: f64,
+    /// Chance to lose a message in a burst, on top of the link probability
+    #[arg(long, default_value_t = DEFAULT_BURST_LOSS)]
+    pub burst_loss: f64,
+}
+
+impl Default for MovingModelParams {
+    fn default() -> Self {
+        MovingModelParams {
+            steps_count: DEFAULT_STEPS_COUNT,
+            agents_count: DEFAULT_AGENTS_COUNT,
+            messages_count: DEFAULT_MESSAGES_COUNT,
+            field_size: DEFAULT_FIELD_SIZE,
+            min_velocity: DEFAULT_MIN_VELOCITY,
+            max_velocity: DEFAULT_MAX_VELOCITY,
+            max_connection_range: DEFAULT_MAX_CONNECTION_RANGE,
+            startup_await: DEFAULT_STARTUP_AWAIT,
+            seed: None,
+            event_driven: true,
+            mac: false,
+            csma_window: DEFAULT_CSMA_WINDOW,
+            battery: DEFAULT_BATTERY,
+            transmit_cost: DEFAULT_TRANSMIT_COST,
+            receive_cost: DEFAULT_RECEIVE_COST,
+            idle_cost: DEFAULT_IDLE_COST,
+            sleep_cost: DEFAULT_SLEEP_COST,
+            mean_uptime: DEFAULT_MEAN_UPTIME,
+            mean_downtime: DEFAULT_MEAN_DOWNTIME,
+            churn_keep_state: false,
+            mobility: MobilityModel::Waypoint,
+            pause_time: DEFAULT_PAUSE_TIME,
+            mobility_interval: DEFAULT_MOBILITY_INTERVAL,
+            gauss_markov_alpha: DEFAULT_GAUSS_MARKOV_ALPHA,
+            streets: DEFAULT_STREETS,
+            group_size: DEFAULT_GROUP_SIZE,
+            group_radius: DEFAULT_GROUP_RADIUS,
+            movements: None,
+            propagation: PropagationModel::Disk,
+            transmit_power: DEFAULT_TRANSMIT_POWER,
+            sensitivity: DEFAULT_SENSITIVITY,
+            frequency: DEFAULT_FREQUENCY,
+            path_loss_exponent: DEFAULT_PATH_LOSS_EXPONENT,
+            reference_distance: DEFAULT_REFERENCE_DISTANCE,
+            antenna_height: DEFAULT_ANTENNA_HEIGHT,
+            shadowing: DEFAULT_SHADOWING,
+            radios: Vec::new(),
+            obstacles: Vec::new(),
+            burst_length: DEFAULT_BURST_LENGTH,
+            burst_gap: DEFAULT_BURST_GAP,
+            burst_loss: DEFAULT_BURST_LOSS,
+        }
+    }
+}
+
+impl MovingModelParams {
+    /// Path loss of the parameters, none for the disk model
+    pub fn propagation(&self) -> Option<Propagation> {
+        let path_loss = match self.propagation {
+            PropagationModel::Disk => return None,
+            PropagationModel::FreeSpace => PathLoss::FreeSpace,
+            PropagationModel::TwoRay => PathLoss::TwoRay {
+                height: self.antenna_height,
+            },
+            PropagationModel::LogDistance => PathLoss::LogDistance {
+                exponent: self.path_loss_exponent,
+                reference: self.reference_distance,
+            },
+        };
+        Some(Propagation {
+            path_loss,
+            transmit_power: self.transmit_power,
+            sensitivity: self.sensitivity,
+            frequency: self.frequency,
+            shadowing: self.shadowing,
+        })
+    }
+
+    /// Radio of every agent
+    pub fn radios(&self) -> Vec<Radio> {
+        let size = self.agents_count as usize;
+        let mut radios = Vec::with_capacity(size);
+        let mut share = 0.;
+        for profile in self.radios.iter() {
+            share += profile.share;
+            let last = ((share * size as f64).round() as usize).min(size);
+            radios.resize(last.max(radios.len()), profile.radio);
+        }
+        radios.resize(
+            size,
+            Radio {
+                range: self.max_connection_range,
+                transmit_power: self.transmit_power,
+                sensitivity: self.sensitivity,
+            },
+        );
+        radios
+    }
+
+    /// Path loss from an agent with the radio `from` to one with `to`
+    fn propagation_between(&self, from: &Radio, to: &Radio) -> Option<Propagation> {
+        self.propagation().map(|propagation| Propagation {
+            transmit_power: from.transmit_power,
+            sensitivity: to.sensitivity,
+            ..propagation
+        })
+    }
+
+    /// Distance the agents with the radio hear each other at, at least half
+    /// the time
+    pub fn range(&self, radio: &Radio) -> f64 {
+        self.propagation_between(radio, radio)
+            .map_or(radio.range, |propagation| propagation.range())
+    }
+}
+
+// a link goes from the one that sends to the one that hears, the radios make
+// them work one way only. The obstacles in the way weaken the signal, on the
+// disk model the range shrinks like in free space
+fn update_connections_via_positions<P: Protocol, R>(
+    model: &mut Model<P, R>,
     agents: &[Agent],
+    radios: &[Radio],
+    obstacles: &Obstacles,
     params: &MovingModelParams,
 ) {
     for (i, a1) in agents.iter().enumerate() {
         for (j, a2) in agents.iter().enumerate() {
             let dst = (a1.position).distance_to(a2.position);
-            // let dst_frac = dst / ((2.0 * params.field_size.powf(2.)).sqrt());
-            let delay = 0;
-            let prob = if dst > params.max_connection_range {
-                0.
-            } else {
-                1.
+            let Some(loss) = obstacles.loss(a1.position, a2.position) else {
+                model.conn.update(i as u32, j as u32, 0., 0);
+                continue;
+            };
+            let (prob, delay) = match params.propagation_between(&radios[i], &radios[j]) {
+                Some(propagation) => Propagation {
+                    transmit_power: propagation.transmit_power - loss,
+                    ..propagation
+                }
+                .link(dst),
+                None if dst > radios[i].range / 10f64.powf(loss / 20.) => (0., 0),
+                None => (1., 0),
             };
-            model.conn.update_both(i as u32, j as u32, prob, delay);
+            model.conn.update(i as u32, j as u32, prob, delay);
         }
     }
 }
 
-fn generate_agents(
-    size: u32,
-    rng: &mut impl Rng,
-    field_random: &impl Distribution<f64>,
-    speed_random: &impl Distribution<f64>,
-) -> Vec<Agent> {
-    std::iter::repeat_with(|| Agent {
-        position: point2(rng.sample(field_random), rng.sample(field_random)),
-        destination: point2(rng.sample(field_random), rng.sample(field_random)),
-        velocity: rng.sample(speed_random),
-    })
-    .take(size as usize)
-    .collect()
+// the trace gets where the agents are and where they head to, the positions
+// in between follow from the speed
+fn record_moves<P: Protocol, R>(
+    model: &mut Model<P, R>,
+    agents: &[Agent],
+    ids: impl IntoIterator<Item = usize>,
+    step: u32,
+    moving: bool,
+) {
+    for id in ids {
+        let agent = &agents[id];
+        model.record(TraceEvent::Move {
+            step,
+            node: id as u32,
+            position: agent.position.to_tuple(),
+            destination: agent.destination.to_tuple(),
+            speed: if moving { agent.velocity } else { 0. },
+        });
+    }
 }
 
-async fn test_moving_random<T: Clone + core::fmt::Debug, R: Rng>(
-    model: &mut Model<T, R>,
-    params: &MovingModelParams,
-) -> (u32, u32, f64) {
-    let field_random = rand_distr::Uniform::new(0.0, params.field_size);
-    let speed_random = rand_distr::Uniform::new(params.min_velocity, params.max_velocity);
+/// The moving scenario run a bit at a time, so it can be watched as it goes
+pub struct MovingRun<P: Protocol, R> {
+    pub model: Model<P, R>,
+    params: MovingModelParams,
+    agents: Vec<Agent>,
+    radios: Vec<Radio>,
+    obstacles: Obstacles,
+    churn: Option<Churn<Exp<f64>, Exp<f64>>>,
+    mobility: Box<dyn Mobility>,
+}
 
-    let mut agents = generate_agents(
-        params.agents_count,
-        &mut model.rng,
-        &field_random,
-        &speed_random,
-    );
+impl<P: Protocol, R: Rng> MovingRun<P, R> {
+    /// Places the agents and runs the model through the startup, up to the
+    /// requests of the messages
+    pub fn new(
+        mut model: Model<P, R>,
+        params: &MovingModelParams,
+    ) -> Result<MovingRun<P, R>, anyhow::Error> {
+        if params.mac {
+            model.set_mac(MacParams {
+                csma: (params.csma_window > 0).then_some(CsmaParams {
+                    window: params.csma_window,
+                    max_attempts: CSMA_MAX_ATTEMPTS,
+                }),
+            });
+        }
+
+        if params.battery > 0. {
+            model.set_energy(EnergyParams {
+                capacity: params.battery,
+                transmit: params.transmit_cost,
+                receive: params.receive_cost,
+                idle: params.idle_cost,
+                sleep: params.sleep_cost,
+            });
+        }
+
+        model.conn.set_default_bursts(bursts(
+            params.burst_gap,
+            params.burst_length,
+            params.burst_loss,
+        ));
+
+        let mut mobility = mobility(params)?;
+        let agents = mobility.place(params.agents_count, &mut model.rng);
 
-    update_connections_via_positions(model, &agents, &params);
+        let radios = params.radios();
+        let obstacles = Obstacles::new(&params.obstacles, params.field_size);
+        update_connections_via_positions(&mut model, &agents, &radios, &obstacles, params);
+        // nobody moves during the startup
+        let step = model.current_step();
+        record_moves(&mut model, &agents, 0..agents.len(), step, false);
 
-    for _ in 0..params.startup_await {
-        model.step().await;
+        model.run_until(params.startup_await);
+
+        send_batch(&mut model, params.messages_count);
+        let step = model.current_step();
+        record_moves(&mut model, &agents, 0..agents.len(), step, true);
+
+        // nodes crash and come back after exponentially distributed times
+        let churn = (params.mean_uptime > 0.).then(|| {
+            Churn::new(
+                &mut model,
+                Exp::new(1. / params.mean_uptime).unwrap(),
+                Exp::new(1. / params.mean_downtime).unwrap(),
+                params.churn_keep_state,
+            )
+        });
+
+        Ok(MovingRun {
+            model,
+            params: params.clone(),
+            agents,
+            radios,
+            obstacles,
+            churn,
+            mobility,
+        })
+    }
+
+    pub fn params(&self) -> &MovingModelParams {
+        &self.params
+    }
+
+    pub fn agents(&self) -> &[Agent] {
+        &self.agents
+    }
+
+    pub fn radios(&self) -> &[Radio] {
+        &self.radios
     }
 
-    send_batch(model, params.messages_count);
+    /// Out of steps or everything is delivered
+    pub fn finished(&self) -> bool {
+        self.model.current_step() >= self.params.steps_count || self.model.stats.all_delivered()
+    }
+
+    /// Moves the agents and runs the model up to the next event, but not past
+    /// `until`. Returns the step the model is at afterwards
+    pub fn advance(&mut self, until: u32) -> u32 {
+        if self.finished() || self.model.current_step() >= until {
+            return self.model.current_step();
+        }
+        let model = &mut self.model;
+        log::debug!("Delivered so far: {}", model.stats.delivered);
 
-    // basically works like a timeout
-    for _ in params.startup_await..params.steps_count {
-        // if all messages that were requested are delivered, break
-        if model.stats.all_delivered() {
-            break;
+        let until = until.min(self.params.steps_count);
+        // in event-driven mode the model jumps over the steps where nothing
+        // happens, but the agents still have to move on every one of them
+        let next = if self.params.event_driven {
+            let churn_event = self.churn.as_ref().and_then(|churn| churn.next_event());
+            [model.next_event(), churn_event]
+                .into_iter()
+                .flatten()
+                .min()
+                .map_or(until, |next| next.min(until))
+        } else {
+            model.current_step() + 1
+        };
+        for step in model.current_step() + 1..next {
+            let turned = self.mobility.step(&mut self.agents, &mut model.rng);
+            record_moves(model, &self.agents, turned, step, true);
         }
-        log::warn!("{}", model.stats.delivered);
 
         // Update connMap
-        update_connections_via_positions(model, &agents, &params);
+        update_connections_via_positions(
+            model,
+            &self.agents,
+            &self.radios,
+            &self.obstacles,
+            &self.params,
+        );
+
+        if let Some(churn) = &mut self.churn {
+            churn.apply(model, next);
+        }
 
         // also you can send additional messages, if you want, like
         // model.request_random();
-        model.step().await;
-
-        // Updating positions
-        for (id, agent) in agents.iter_mut().enumerate() {
-            log::debug!(
-                "Position: {}\t{}",
-                id,
-                (agent.position - agent.destination).length()
-            );
-            let mut steps_remaining = 1.0;
-            loop {
-                let remaining_dist = agent.destination.distance_to(agent.position);
-                if remaining_dist > (steps_remaining * agent.velocity) {
-                    // Nothing changes;
-                    let mut direction = agent.destination - agent.position;
-                    direction = direction / direction.length() * steps_remaining * agent.velocity;
-                    agent.position += direction;
-                    break;
-                } else {
-                    steps_remaining -= remaining_dist / agent.velocity;
-                    agent.position = agent.destination;
-                    agent.destination = point2(
-                        model.rng.sample(field_random),
-                        model.rng.sample(field_random),
-                    );
-                    agent.velocity = model.rng.sample(speed_random);
-
-                    log::info!("Achieved {}", id);
-                }
-            }
+        model.advance(next);
+
+        let turned = self.mobility.step(&mut self.agents, &mut model.rng);
+        record_moves(model, &self.agents, turned, next, true);
+        next
+    }
+
+    /// Runs until the run is finished
+    pub fn run(&mut self) {
+        // basically works like a timeout
+        while !self.finished() {
+            self.advance(self.params.steps_count);
         }
+        log::info!("{} {:?}", std::any::type_name::<P>(), self.model.stats);
     }
-    log::info!("{} {:?}", std::any::type_name::<T>(), model.stats);
-    (
-        model.stats.total,
-        model.stats.delivered,
-        model.stats.avg_delivery_time(),
-    )
 }
 
-#[tokio::test]
-async fn test_moving() {
+#[cfg(test)]
+fn check_moving<P: Protocol>(params: &MovingModelParams, seed: u64) {
+    let stats = run_moving::<P>(params, &Default::default(), seed, None).unwrap();
+    // the same again for the same seed
+    assert_eq!(
+        stats,
+        run_moving::<P>(params, &Default::default(), seed, None).unwrap()
+    );
+    assert_eq!(stats.messages.len() as u32, params.messages_count);
+    let delivered: Vec<_> = stats.messages.values().filter(|m| m.delivered).collect();
+    assert_eq!(delivered.len() as u32, stats.delivered);
+    for message in delivered {
+        assert!(message.steps <= params.steps_count);
+        assert!(message.hops >= 1);
+    }
+    // 10 agents on the default field mostly meet within a minute
+    assert!(stats.delivered * 2 >= params.messages_count, "{:?}", stats);
+}
+
+#[test]
+fn test_moving() {
     let _ = env_logger::builder().try_init();
 
     let params = MovingModelParams {
-        steps_count: get_parse_or("STEPS_COUNT", DEFAULT_STEPS_COUNT).unwrap(),
-        agents_count: get_parse_or("AGENTS_COUNT", DEFAULT_AGENTS_COUNT).unwrap(),
-        messages_count: get_parse_or("MESSAGES_COUNT", DEFAULT_MESSAGES_COUNT).unwrap(),
-        field_size: get_parse_or("FIELD_SIZE", DEFAULT_FIELD_SIZE).unwrap(),
-        min_velocity: get_parse_or("MIN_VELOCITY", DEFAULT_MIN_VELOCITY).unwrap(),
-        max_velocity: get_parse_or("MAX_VELOCITY", DEFAULT_MAX_VELOCITY).unwrap(),
-        max_connection_range: get_parse_or("MAX_CONNECTION_RANGE", DEFAULT_MAX_CONNECTION_RANGE)
-            .unwrap(),
-        startup_await: get_parse_or("STARTUP_AWAIT", DEFAULT_STARTUP_AWAIT).unwrap(),
-        seed: envmnt::get_parse("SEED").ok(),
+        steps_count: 60 * 1000,
+        ..Default::default()
     };
-    log::info!("{:?}", params);
+    for seed in 1..=2 {
+        check_moving::<GossipNode>(&params, seed);
+        check_moving::<DSDVNode>(&params, seed);
+        check_moving::<CBRNode>(&params, seed);
+    }
+}
 
-    let get_rng = || match params.seed {
-        Some(seed) => StdRng::seed_from_u64(seed),
-        None => StdRng::from_entropy(),
+pub fn run_moving<P: Protocol>(
+    params: &MovingModelParams,
+    protocol: &P::Params,
+    seed: u64,
+    trace: Option<Trace>,
+) -> Result<Stats, anyhow::Error> {
+    let mut model = Model::<P, _>::with_params(
+        params.agents_count,
+        protocol.clone(),
+        StdRng::seed_from_u64(seed),
+    );
+    if let Some(trace) = trace {
+        model.set_trace(trace);
+    }
+    let mut run = MovingRun::new(model, params)?;
+    run.run();
+    let mut model = run.model;
+    model.take_trace().map(Trace::finish).transpose()?;
+    Ok(model.stats)
+}
+
+#[cfg(test)]
+fn run_both_modes<P: Protocol>(params: &MovingModelParams) {
+    let seed = params.seed.unwrap();
+    let model = || Model::<P, _>::new(params.agents_count, StdRng::seed_from_u64(seed));
+    let mut stepped = MovingRun::new(
+        model(),
+        &MovingModelParams {
+            event_driven: false,
+            ..params.clone()
+        },
+    )
+    .unwrap();
+    stepped.run();
+    let mut event_driven = MovingRun::new(
+        model(),
+        &MovingModelParams {
+            event_driven: true,
+            ..params.clone()
+        },
+    )
+    .unwrap();
+    event_driven.run();
+    assert_eq!(
+        stepped.model.current_step(),
+        event_driven.model.current_step()
+    );
+    assert_eq!(stepped.model.stats, event_driven.model.stats);
+
+    // a few steps at a time, like when watched
+    let mut watched = MovingRun::new(model(), params).unwrap();
+    let mut until = 0;
+    while !watched.finished() {
+        until += 137;
+        while watched.advance(until) < until && !watched.finished() {}
+    }
+    assert_eq!(watched.model.stats, event_driven.model.stats);
+}
+
+#[test]
+fn test_event_driven() {
+    let _ = env_logger::builder().try_init();
+
+    // fast agents, collisions, batteries running out and nodes coming and
+    // going
+    let params = MovingModelParams {
+        steps_count: 20000,
+        min_velocity: DEFAULT_MIN_VELOCITY * 10.,
+        max_velocity: DEFAULT_MAX_VELOCITY * 10.,
+        startup_await: 1000,
+        seed: Some(7),
+        mac: true,
+        csma_window: 10,
+        battery: 5.,
+        mean_uptime: 3000.,
+        mean_downtime: 1000.,
+        ..Default::default()
     };
+    run_both_modes::<GossipNode>(&params);
+    run_both_modes::<DSDVNode>(&params);
+
+    // recorded movements: the agents cross the field and back, a second apart
+    let path = std::env::temp_dir().join(format!("moving-{}.movements", std::process::id()));
+    let lines: Vec<String> = (0..params.agents_count)
+        .map(|id| {
+            let (y, start) = (id as f64 * 10., id as f64);
+            format!(
+                "{} 0 {} {} 100 {} {} 0 {}",
+                start,
+                y,
+                start + 10.,
+                y,
+                start + 20.,
+                y
+            )
+        })
+        .collect();
+    std::fs::write(&path, lines.join("\n")).unwrap();
+
+    let cases = [
+        // squads that stop now and then
+        (
+            run_both_modes::<GossipNode> as fn(&MovingModelParams),
+            MovingModelParams {
+                mobility: MobilityModel::Group,
+                pause_time: 2000,
+                group_size: 3,
+                ..params.clone()
+            },
+        ),
+        // links fading out around 30 meters
+        (
+            run_both_modes::<DSDVNode>,
+            MovingModelParams {
+                propagation: PropagationModel::LogDistance,
+                transmit_power: 15.,
+                shadowing: 4.,
+                ..params.clone()
+            },
+        ),
+        // a few agents heard much further than they hear
+        (
+            run_both_modes::<DSDVNode>,
+            MovingModelParams {
+                radios: vec!["0.3:60:0:-70".parse().unwrap()],
+                ..params.clone()
+            },
+        ),
+        // a building the signal goes through and a wall it doesn't
+        (
+            run_both_modes::<DSDVNode>,
+            MovingModelParams {
+                obstacles: vec![
+                    "20,20,50,20,50,50,20,50:6".parse().unwrap(),
+                    "70,0,70,80".parse().unwrap(),
+                ],
+                ..params.clone()
+            },
+        ),
+        // links dropping out for a tenth of a second now and then
+        (
+            run_both_modes::<CBRNode>,
+            MovingModelParams {
+                burst_length: 100.,
+                burst_gap: 900.,
+                ..params.clone()
+            },
+        ),
+        // following the recording
+        (
+            run_both_modes::<DSDVNode>,
+            MovingModelParams {
+                movements: Some(path.clone()),
+                ..params
+            },
+        ),
+    ];
+    for (run_both_modes, params) in cases.iter() {
+        run_both_modes(params);
+    }
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn asymmetric() {
+    let _ = env_logger::builder().try_init();
 
-    let measurements = get_parse_or("MEASUREMENTS", 100).unwrap();
+    let params = MovingModelParams {
+        agents_count: 10,
+        radios: vec![
+            "0.2:100:20:-90".parse().unwrap(),
+            "0.25:50:10:-80".parse().unwrap(),
+        ],
+        ..Default::default()
+    };
+    let ranges: Vec<f64> = params.radios().iter().map(|radio| radio.range).collect();
+    assert_eq!(ranges, [100., 100., 50., 50., 50., 30., 30., 30., 30., 30.]);
+    assert!("0.2:100:20".parse::<RadioProfile>().is_err());
 
-    for _ in 0..measurements {
-        let (a, b, c) = test_moving_random(
-            &mut generate_gossip_model(params.agents_count, get_rng()),
+    // a vehicle and a handheld 40 meters apart, on the disk model and with
+    // the path loss
+    let agents: Vec<Agent> = [(0., 0.), (40., 0.)]
+        .into_iter()
+        .map(|(x, y)| Agent {
+            position: euclid::point2(x, y),
+            destination: euclid::point2(x, y),
+            velocity: 0.,
+        })
+        .collect();
+    let params = MovingModelParams {
+        agents_count: 2,
+        radios: vec!["0.5:100:20:-60".parse().unwrap()],
+        ..params
+    };
+    let radios = params.radios();
+    for propagation in [PropagationModel::Disk, PropagationModel::FreeSpace] {
+        let params = MovingModelParams {
+            propagation,
+            ..params.clone()
+        };
+        let mut model = Model::<DSDVNode, _>::new(2, StdRng::seed_from_u64(1));
+        update_connections_via_positions(
+            &mut model,
+            &agents,
+            &radios,
+            &Obstacles::default(),
             &params,
-        )
-        .await;
-        println!("{} {} {}", a, b, c);
-    }
-    println!("===");
-    for _ in 0..measurements {
-        let (a, b, c) = test_moving_random(
-            &mut generate_dsdv_model(params.agents_count, get_rng()),
+        );
+        assert_eq!(model.conn.prob(0, 1), 1.);
+        assert_eq!(model.conn.prob(1, 0), 0.);
+
+        // the handheld learns a route to the vehicle it can't reach
+        model.run_until(2000);
+        model.request_message(1, 0);
+        model.run_until(10000);
+        assert!(!model.stats.all_delivered());
+        assert!(model.stats.one_way > 0);
+        assert!(model.stats.one_way_unicasts > 0);
+    }
+}
+
+#[test]
+fn line_of_sight() {
+    let _ = env_logger::builder().try_init();
+
+    // 20 meters apart, within the range, with a wall or a building between
+    let agents: Vec<Agent> = [(10., 10.), (30., 10.), (10., 50.), (30., 50.)]
+        .into_iter()
+        .map(|(x, y)| Agent {
+            position: euclid::point2(x, y),
+            destination: euclid::point2(x, y),
+            velocity: 0.,
+        })
+        .collect();
+    let params = MovingModelParams {
+        agents_count: 4,
+        obstacles: vec![
+            "20,0,20,20".parse().unwrap(),
+            "15,45,25,45,25,55,15,55:6".parse().unwrap(),
+        ],
+        ..Default::default()
+    };
+    let obstacles = Obstacles::new(&params.obstacles, params.field_size);
+    for propagation in [PropagationModel::Disk, PropagationModel::FreeSpace] {
+        let params = MovingModelParams {
+            propagation,
+            ..params.clone()
+        };
+        let mut model = Model::<DSDVNode, _>::new(4, StdRng::seed_from_u64(1));
+        update_connections_via_positions(
+            &mut model,
+            &agents,
+            &params.radios(),
+            &obstacles,
             &params,
-        )
-        .await;
-        println!("{} {} {}", a, b, c);
+        );
+        assert_eq!(model.conn.prob(0, 1), 0.);
+        assert_eq!(model.conn.prob(1, 0), 0.);
+        assert_eq!(model.conn.prob(0, 2), 0.);
+        // 6 dB halve the range to about 15 meters
+        assert_eq!(model.conn.prob(2, 3), 0.);
+        assert_eq!(model.conn.prob(3, 2), 0.);
+        assert_eq!(model.conn.prob(1, 3), 0.);
     }
+    // but 10 meters get through
+    let agents = [agents[2], agents[3]].map(|agent| Agent {
+        position: euclid::point2(agent.position.x * 0.5 + 7.5, agent.position.y),
+        ..agent
+    });
+    let mut model = Model::<DSDVNode, _>::new(2, StdRng::seed_from_u64(1));
+    let params = MovingModelParams {
+        agents_count: 2,
+        ..params
+    };
+    update_connections_via_positions(&mut model, &agents, &params.radios(), &obstacles, &params);
+    assert_eq!(model.conn.prob(0, 1), 1.);
 }
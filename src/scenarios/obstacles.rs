@@ -0,0 +1,272 @@
+use euclid::*;
+use serde::{Deserialize, Serialize};
+
+// how far from the corners of the obstacles the paths around them go, in meters
+const CORNER_MARGIN: f64 = 0.5;
+
+type Point = Point2D<f64, UnknownUnit>;
+
+/// A building or a wall of the field
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Obstacle {
+    /// Corners in order around it, in meters
+    pub polygon: Vec<(f64, f64)>,
+    /// dB the signal loses going through it, none blocks it completely
+    #[serde(default)]
+    pub attenuation: Option<f64>,
+}
+
+/// `x,y,x,y,...` of the corners, then `:attenuation` if it doesn't block
+impl std::str::FromStr for Obstacle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (corners, attenuation) = match s.split_once(':') {
+            Some((corners, attenuation)) => (
+                corners,
+                Some(
+                    attenuation
+                        .parse::<f64>()
+                        .map_err(|err| format!("{}: {}", attenuation, err))?,
+                ),
+            ),
+            None => (s, None),
+        };
+        let values = corners
+            .split(',')
+            .map(|value| {
+                value
+                    .parse::<f64>()
+                    .map_err(|err| format!("{}: {}", value, err))
+            })
+            .collect::<Result<Vec<f64>, _>>()?;
+        if values.len() < 4 || values.len() % 2 != 0 {
+            return Err("expected x,y pairs of at least two corners".to_string());
+        }
+        Ok(Obstacle {
+            polygon: values.chunks(2).map(|xy| (xy[0], xy[1])).collect(),
+            attenuation,
+        })
+    }
+}
+
+impl Obstacle {
+    fn corners(&self) -> impl Iterator<Item = Point> + Clone + '_ {
+        self.polygon.iter().map(|&(x, y)| point2(x, y))
+    }
+
+    fn edges(&self) -> impl Iterator<Item = (Point, Point)> + '_ {
+        self.corners().zip(self.corners().cycle().skip(1))
+    }
+
+    /// Whether the point is inside, a wall of two corners has no inside
+    pub fn contains(&self, point: Point) -> bool {
+        let mut inside = false;
+        for (a, b) in self.edges() {
+            if (a.y > point.y) != (b.y > point.y)
+                && point.x < a.x + (point.y - a.y) / (b.y - a.y) * (b.x - a.x)
+            {
+                inside = !inside;
+            }
+        }
+        inside
+    }
+
+    /// Whether the straight line from `a` to `b` goes through it
+    pub fn crosses(&self, a: Point, b: Point) -> bool {
+        self.edges().any(|(c, d)| cross(a, b, c, d))
+            || self.contains(a)
+            || self.contains(b)
+            // through two of the corners and nothing else
+            || self.contains(a.lerp(b, 0.5))
+    }
+}
+
+// which side of the line from `a` to `b` the point is on
+fn side(a: Point, b: Point, point: Point) -> f64 {
+    (b - a).cross(point - a)
+}
+
+// whether the segments cross each other, touching doesn't count
+fn cross(a: Point, b: Point, c: Point, d: Point) -> bool {
+    side(a, b, c) * side(a, b, d) < 0. && side(c, d, a) * side(c, d, b) < 0.
+}
+
+/// The obstacles of the field, with the spots next to their corners the
+/// paths around them go through
+#[derive(Debug, Clone, Default)]
+pub struct Obstacles {
+    pub obstacles: Vec<Obstacle>,
+    corners: Vec<Point>,
+}
+
+impl Obstacles {
+    pub fn new(obstacles: &[Obstacle], field_size: f64) -> Self {
+        let mut corners = Vec::new();
+        for obstacle in obstacles.iter() {
+            let polygon: Vec<Point> = obstacle.corners().collect();
+            let count = polygon.len();
+            for (i, &corner) in polygon.iter().enumerate() {
+                let previous = polygon[(i + count - 1) % count];
+                let next = polygon[(i + 1) % count];
+                // along the bisector, away from the obstacle
+                let mut outwards = (corner - previous).normalize() + (corner - next).normalize();
+                if outwards.square_length() < 1e-12 {
+                    // the end of a wall or a straight corner
+                    outwards = (corner - next).normalize();
+                }
+                let mut spot = corner + outwards.normalize() * CORNER_MARGIN;
+                if obstacle.contains(spot) {
+                    spot = corner - outwards.normalize() * CORNER_MARGIN;
+                }
+                let inside_field =
+                    (0. ..=field_size).contains(&spot.x) && (0. ..=field_size).contains(&spot.y);
+                if inside_field && obstacles.iter().all(|obstacle| !obstacle.contains(spot)) {
+                    corners.push(spot);
+                }
+            }
+        }
+        Obstacles {
+            obstacles: obstacles.to_vec(),
+            corners,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.obstacles.is_empty()
+    }
+
+    /// Whether the point is inside any of the obstacles
+    pub fn inside(&self, point: Point) -> bool {
+        self.obstacles
+            .iter()
+            .any(|obstacle| obstacle.contains(point))
+    }
+
+    /// Whether nothing is in the way from `a` to `b`
+    pub fn clear(&self, a: Point, b: Point) -> bool {
+        self.obstacles
+            .iter()
+            .all(|obstacle| !obstacle.crosses(a, b))
+    }
+
+    /// dB the signal from `a` to `b` loses through the obstacles in the way,
+    /// none if one of them blocks it
+    pub fn loss(&self, a: Point, b: Point) -> Option<f64> {
+        let mut loss = 0.;
+        for obstacle in self.obstacles.iter() {
+            if obstacle.crosses(a, b) {
+                loss += obstacle.attenuation?;
+            }
+        }
+        Some(loss)
+    }
+
+    /// Shortest way from `from` to `to` around the obstacles: the spots to
+    /// go through in a straight line, `to` the last. None if there's no way
+    pub fn route(&self, from: Point, to: Point) -> Option<Vec<Point>> {
+        if self.clear(from, to) {
+            return Some(vec![to]);
+        }
+        // Dijkstra over the corners, `from` is the first spot and `to` the last
+        let mut spots = vec![from];
+        spots.extend(self.corners.iter().cloned());
+        spots.push(to);
+        let mut distances = vec![f64::INFINITY; spots.len()];
+        let mut previous = vec![None; spots.len()];
+        let mut done = vec![false; spots.len()];
+        distances[0] = 0.;
+        while let Some(spot) = (0..spots.len())
+            .filter(|&i| !done[i] && distances[i].is_finite())
+            .min_by(|&a, &b| distances[a].total_cmp(&distances[b]))
+        {
+            done[spot] = true;
+            if spot == spots.len() - 1 {
+                break;
+            }
+            for next in 0..spots.len() {
+                if done[next] {
+                    continue;
+                }
+                let distance = distances[spot] + spots[spot].distance_to(spots[next]);
+                if distance < distances[next] && self.clear(spots[spot], spots[next]) {
+                    distances[next] = distance;
+                    previous[next] = Some(spot);
+                }
+            }
+        }
+
+        let mut route = Vec::new();
+        let mut spot = spots.len() - 1;
+        while spot != 0 {
+            route.push(spots[spot]);
+            spot = previous[spot]?;
+        }
+        route.reverse();
+        Some(route)
+    }
+}
+
+#[test]
+fn obstacles() {
+    let _ = env_logger::builder().try_init();
+
+    // a building losing 10 dB and a wall blocking everything
+    let building: Obstacle = "20,20,40,20,40,40,20,40:10".parse().unwrap();
+    let wall: Obstacle = "60,0,60,80".parse().unwrap();
+    assert_eq!(building.attenuation, Some(10.));
+    assert_eq!(wall.polygon, [(60., 0.), (60., 80.)]);
+    assert!("1,2,3".parse::<Obstacle>().is_err());
+    assert!("1,2,3,4:x".parse::<Obstacle>().is_err());
+
+    let obstacles = Obstacles::new(&[building, wall], 100.);
+    assert!(obstacles.inside(point2(30., 30.)));
+    assert!(!obstacles.inside(point2(60., 50.)));
+    assert!(!obstacles.inside(point2(50., 30.)));
+
+    assert_eq!(obstacles.loss(point2(10., 10.), point2(50., 10.)), Some(0.));
+    assert_eq!(
+        obstacles.loss(point2(10., 30.), point2(50., 30.)),
+        Some(10.)
+    );
+    // across a corner, through two of the corners, from inside
+    assert_eq!(
+        obstacles.loss(point2(15., 30.), point2(30., 15.)),
+        Some(10.)
+    );
+    assert_eq!(
+        obstacles.loss(point2(10., 10.), point2(50., 50.)),
+        Some(10.)
+    );
+    assert_eq!(
+        obstacles.loss(point2(30., 30.), point2(30., 50.)),
+        Some(10.)
+    );
+    assert_eq!(obstacles.loss(point2(50., 30.), point2(70., 30.)), None);
+    assert_eq!(obstacles.loss(point2(50., 90.), point2(70., 90.)), Some(0.));
+
+    // straight when nothing is in the way, around the corners otherwise
+    let (from, to) = (point2(10., 30.), point2(50., 30.));
+    assert_eq!(
+        obstacles.route(from, point2(10., 50.)),
+        Some(vec![point2(10., 50.)])
+    );
+    let route = obstacles.route(from, to).unwrap();
+    assert_eq!(route.len(), 3);
+    assert_eq!(*route.last().unwrap(), to);
+    let mut length = 0.;
+    let mut last = from;
+    for &spot in route.iter() {
+        assert!(obstacles.clear(last, spot));
+        length += last.distance_to(spot);
+        last = spot;
+    }
+    assert!(length < 50.);
+    // around the end of the wall
+    let route = obstacles.route(point2(50., 30.), point2(70., 30.)).unwrap();
+    assert!(route[0].y > 80.);
+    // nowhere to go from inside a closed room
+    let room: Obstacle = "0,0,100,0,100,100,0,100".parse().unwrap();
+    let closed = Obstacles::new(&[room], 200.);
+    assert!(closed.route(point2(50., 50.), point2(150., 50.)).is_none());
+}
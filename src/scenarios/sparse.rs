@@ -1,6 +1,284 @@
-#[tokio::test]
-async fn todo() {
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+#[cfg(test)]
+use envmnt::get_parse_or;
+
+#[cfg(test)]
+use crate::export::*;
+use crate::model::*;
+#[cfg(test)]
+use crate::protocols::*;
+use crate::scenarios::*;
+
+use clap::{Args, ValueEnum};
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_STEPS_COUNT: u32 = 1000 * 60; /* a minute */
+const DEFAULT_NODES_COUNT: u32 = 30;
+const DEFAULT_MESSAGES_COUNT: u32 = 10;
+const DEFAULT_DEGREE: f64 = 4.;
+const DEFAULT_CLUSTER_SIZE: u32 = 4;
+const DEFAULT_LINK_PROBABILITY: f64 = 0.9;
+const DEFAULT_STARTUP_AWAIT: u32 = 5 * 1000; /* 5 seconds for a startup */
+const DEFAULT_BURST_LENGTH: f64 = 0.; /* independent losses */
+const DEFAULT_BURST_GAP: f64 = 1000.; /* a second */
+const DEFAULT_BURST_LOSS: f64 = 1.;
+// failed attempts at a connected geometric graph before the radius grows
+const GEOMETRIC_ATTEMPTS: u32 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SparseTopology {
+    /// Nodes at random spots of a square, linked to the ones close enough
+    Geometric,
+    /// Every node linked to the previous and the next one
+    Line,
+    /// Fully linked clusters, every one linked to the next by a single link
+    Chain,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Args)]
+#[serde(default)]
+pub struct SparseModelParams {
+    /// Steps to run for at most, a step is a millisecond
+    #[arg(long, default_value_t = DEFAULT_STEPS_COUNT)]
+    pub steps_count: u32,
+    #[arg(long, value_enum, default_value_t = SparseTopology::Geometric)]
+    pub topology: SparseTopology,
+    #[arg(long, default_value_t = DEFAULT_NODES_COUNT, value_parser = clap::value_parser!(u32).range(2..))]
+    pub nodes_count: u32,
+    /// Messages requested between random nodes after the startup
+    #[arg(long, default_value_t = DEFAULT_MESSAGES_COUNT)]
+    pub messages_count: u32,
+    /// Average number of neighbours in the geometric topology
+    #[arg(long, default_value_t = DEFAULT_DEGREE)]
+    pub degree: f64,
+    /// Nodes in every cluster of the chain topology
+    #[arg(long, default_value_t = DEFAULT_CLUSTER_SIZE, value_parser = clap::value_parser!(u32).range(1..))]
+    pub cluster_size: u32,
+    /// Probability of a message getting through any of the links
+    #[arg(long, default_value_t = DEFAULT_LINK_PROBABILITY)]
+    pub link_probability: f64,
+    /// Steps the protocols get to settle before the messages are requested
+    #[arg(long, default_value_t = DEFAULT_STARTUP_AWAIT)]
+    pub startup_await: u32,
+    #[arg(long)]
+    pub seed: Option<u64>,
+    /// Mean steps a link stays bad in a burst of losses, 0 loses messages
+    /// independently
+    #[arg(long, default_value_t = DEFAULT_BURST_LENGTH)]
+    pub burst_length: f64,
+    /// Mean steps between the bursts
+    #[arg(long, default_value_t = DEFAULT_BURST_GAP)]
+    pub burst_gap: f64,
+    /// Chance to lose a message in a burst, on top of the link probability
+    #[arg(long, default_value_t = DEFAULT_BURST_LOSS)]
+    pub burst_loss: f64,
+}
+
+impl Default for SparseModelParams {
+    fn default() -> Self {
+        SparseModelParams {
+            steps_count: DEFAULT_STEPS_COUNT,
+            topology: SparseTopology::Geometric,
+            nodes_count: DEFAULT_NODES_COUNT,
+            messages_count: DEFAULT_MESSAGES_COUNT,
+            degree: DEFAULT_DEGREE,
+            cluster_size: DEFAULT_CLUSTER_SIZE,
+            link_probability: DEFAULT_LINK_PROBABILITY,
+            startup_await: DEFAULT_STARTUP_AWAIT,
+            seed: None,
+            burst_length: DEFAULT_BURST_LENGTH,
+            burst_gap: DEFAULT_BURST_GAP,
+            burst_loss: DEFAULT_BURST_LOSS,
+        }
+    }
+}
+
+/// Random geometric graph in a unit square with `degree` neighbours per node
+/// on average: the radius is picked to give as many links. Graphs that come
+/// out disconnected are thrown away, and the links get a bit more numerous
+/// every few of them, so the result is always connected
+pub fn geometric(size: u32, degree: f64, rng: &mut impl Rng) -> Graph {
+    let pairs = (size * (size - 1) / 2) as usize;
+    let mut count = ((degree * size as f64 / 2.).round() as usize).clamp(size as usize - 1, pairs);
+    let mut attempts = 0;
+    loop {
+        let positions: Vec<(f64, f64)> = (0..size).map(|_| (rng.gen(), rng.gen())).collect();
+        let mut links: Vec<(f64, (u32, u32))> = Vec::with_capacity(pairs);
+        for i in 0..size {
+            for j in i + 1..size {
+                let (a, b) = (positions[i as usize], positions[j as usize]);
+                links.push(((a.0 - b.0).hypot(a.1 - b.1), (i, j)));
+            }
+        }
+        links.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let graph = Graph {
+            positions,
+            links: links[..count].iter().map(|(_, link)| *link).collect(),
+        };
+        if graph.connected() {
+            return graph;
+        }
+        attempts += 1;
+        if attempts % GEOMETRIC_ATTEMPTS == 0 {
+            log::info!("no connected graph with {} links, adding some", count);
+            count = (count + count / 10 + 1).min(pairs);
+        }
+    }
+}
+
+/// Clusters of `cluster` nodes, the last one may be smaller. The last node of
+/// every cluster is linked to the first node of the next one
+pub fn chain(size: u32, cluster: u32) -> Graph {
+    let mut graph = Graph::default();
+    for i in 0..size {
+        let (index, place) = (i / cluster, i % cluster);
+        let angle = std::f64::consts::TAU * place as f64 / cluster as f64;
+        graph
+            .positions
+            .push((index as f64 * 3. + angle.cos(), angle.sin()));
+        for j in index * cluster..i {
+            graph.links.push((j, i));
+        }
+        if place == 0 && i > 0 {
+            graph.links.push((i - 1, i));
+        }
+    }
+    graph
+}
+
+pub fn generate(params: &SparseModelParams, rng: &mut impl Rng) -> Graph {
+    match params.topology {
+        SparseTopology::Geometric => geometric(params.nodes_count, params.degree, rng),
+        SparseTopology::Line => line(params.nodes_count),
+        SparseTopology::Chain => chain(params.nodes_count, params.cluster_size),
+    }
+}
+
+// links the nodes of the model like the graph, the positions go to the trace
+fn connect<P: Protocol, R>(model: &mut Model<P, R>, graph: &Graph, probability: f64) {
+    graph.fill(&mut model.conn, Link::new(probability as f32, 0));
+    let step = model.current_step();
+    for (node, &position) in graph.positions.iter().enumerate() {
+        model.record(TraceEvent::Move {
+            step,
+            node: node as u32,
+            position,
+            destination: position,
+            speed: 0.,
+        });
+    }
+}
+
+pub fn run_sparse<P: Protocol>(
+    params: &SparseModelParams,
+    protocol: &P::Params,
+    seed: u64,
+    trace: Option<Trace>,
+) -> Result<Stats, anyhow::Error> {
+    let mut model = Model::<P, _>::with_params(
+        params.nodes_count,
+        protocol.clone(),
+        StdRng::seed_from_u64(seed),
+    );
+    if let Some(trace) = trace {
+        model.set_trace(trace);
+    }
+    let graph = generate(params, &mut model.rng);
+    connect(&mut model, &graph, params.link_probability);
+    model.conn.set_default_bursts(bursts(
+        params.burst_gap,
+        params.burst_length,
+        params.burst_loss,
+    ));
+    model.run_until(params.startup_await);
+    send_batch(&mut model, params.messages_count);
+    while model.current_step() < params.steps_count && !model.stats.all_delivered() {
+        model.advance(params.steps_count);
+    }
+    model.take_trace().map(Trace::finish).transpose()?;
+    Ok(model.stats)
+}
+
+#[test]
+fn topologies() {
+    let _ = env_logger::builder().try_init();
+
+    let mut rng = StdRng::seed_from_u64(1);
+    for degree in [2., 4., 8.] {
+        for _ in 0..10 {
+            let graph = geometric(50, degree, &mut rng);
+            assert!(graph.connected());
+            // the borders cut the neighbourhoods, connecting adds a few
+            assert!(graph.average_degree() >= degree);
+            // sparser ones need more links to be connected
+            if degree >= 8. {
+                assert!((graph.average_degree() - degree).abs() < 1e-9);
+            }
+        }
+    }
+
+    // 3 clusters of 4 and one of 2: 3 * 6 + 1 inside, 3 between
+    let graph = chain(14, 4);
+    assert_eq!(graph.links.len(), 22);
+    assert_eq!(graph.diameter(), Some(7));
+    assert_eq!(chain(5, 1).diameter(), Some(4));
+}
+
+#[test]
+fn test_sparse() {
     let _ = env_logger::builder().try_init();
 
-    // TODO:
+    let base = SparseModelParams {
+        steps_count: get_parse_or("STEPS_COUNT", 20000).unwrap(),
+        nodes_count: get_parse_or("NODES_COUNT", 12).unwrap(),
+        messages_count: get_parse_or("MESSAGES_COUNT", DEFAULT_MESSAGES_COUNT).unwrap(),
+        seed: Some(get_parse_or("SEED", 1).unwrap()),
+        ..Default::default()
+    };
+    let measurements = get_parse_or("MEASUREMENTS", 1).unwrap();
+    // OUTPUT=out/results.csv (or .jsonl) appends a record for every run
+    let mut export =
+        envmnt::exists("OUTPUT").then(|| Exporter::create(envmnt::get_or("OUTPUT", "")).unwrap());
+
+    let mut densities: Vec<SparseModelParams> = [3., 6.]
+        .into_iter()
+        .map(|degree| SparseModelParams { degree, ..base })
+        .collect();
+    densities.push(SparseModelParams {
+        topology: SparseTopology::Line,
+        ..base
+    });
+    densities.push(SparseModelParams {
+        topology: SparseTopology::Chain,
+        ..base
+    });
+    // as many losses, but in bursts of a fifth of a second
+    densities.push(SparseModelParams {
+        link_probability: 1.,
+        burst_length: 200.,
+        burst_gap: 1800.,
+        ..base
+    });
+    for params in densities.iter() {
+        log::info!("{:?}", params);
+        for _ in 0..measurements {
+            measure::<GossipNode>(params, export.as_mut());
+            measure::<DSDVNode>(params, export.as_mut());
+            measure::<CBRNode>(params, export.as_mut());
+        }
+    }
+}
+
+#[cfg(test)]
+fn measure<P: Protocol>(params: &SparseModelParams, export: Option<&mut Exporter>) {
+    // without a fixed seed every run gets its own, so it can be repeated
+    let seed = params.seed.unwrap_or_else(|| rand::thread_rng().gen());
+    let stats = run_sparse::<P>(params, &Default::default(), seed, None).unwrap();
+    let record = Record::new::<P>(seed, params, stats);
+    println!("{}", record.summary());
+    if let Some(export) = export {
+        export.write(&record).unwrap();
+    }
 }
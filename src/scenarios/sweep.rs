@@ -0,0 +1,183 @@
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::export::*;
+use crate::scenarios::experiment::*;
+
+/// A set of experiments that differ in a few parameters, as stored in a sweep
+/// file like `experiments/sweep-messages.json`.
+///
+/// Values are set by dotted paths into the experiment, like
+/// `"scenario.messages_count"` or `"protocol"`. Every point of `points` is
+/// combined with every combination of the values in `grid`, and every
+/// resulting experiment is run `runs` times.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sweep {
+    /// Experiment the values are set in, its runs, output and trace are
+    /// ignored
+    pub base: Value,
+    #[serde(default)]
+    pub grid: Map<String, Value>,
+    #[serde(default)]
+    pub points: Vec<Map<String, Value>>,
+    #[serde(default = "one")]
+    pub runs: u64,
+    /// The seeds of all the runs are derived from it
+    #[serde(default)]
+    pub seed: u64,
+    /// csv or jsonl file all the runs go to, runs already there are skipped
+    pub output: PathBuf,
+}
+
+// sets the value at a dotted path like "scenario.messages_count"
+fn set(target: &mut Value, path: &str, value: Value) -> Result<(), anyhow::Error> {
+    let mut target = target;
+    for key in path.split('.') {
+        target = target
+            .as_object_mut()
+            .ok_or_else(|| anyhow::anyhow!("{} is not inside an object", path))?
+            .entry(key)
+            .or_insert(Value::Null);
+    }
+    *target = value;
+    Ok(())
+}
+
+// FNV-1a, stable between builds unlike the std hasher
+fn hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+// splitmix64
+fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e3779b97f4a7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+impl Sweep {
+    pub fn load(path: impl AsRef<std::path::Path>) -> Result<Sweep, anyhow::Error> {
+        let path = path.as_ref();
+        let file = std::fs::File::open(path)
+            .map_err(|err| anyhow::anyhow!("can't open {}: {}", path.display(), err))?;
+        Ok(serde_json::from_reader(std::io::BufReader::new(file))?)
+    }
+
+    /// Values of every point, the grid expanded
+    pub fn points(&self) -> Result<Vec<Map<String, Value>>, anyhow::Error> {
+        let mut points = match self.points.is_empty() {
+            true => vec![Map::new()],
+            false => self.points.clone(),
+        };
+        for (path, values) in self.grid.iter() {
+            let values = values
+                .as_array()
+                .ok_or_else(|| anyhow::anyhow!("values of {} are not a list", path))?;
+            points = points
+                .into_iter()
+                .flat_map(|point| {
+                    values.iter().map(move |value| {
+                        let mut point = point.clone();
+                        point.insert(path.clone(), value.clone());
+                        point
+                    })
+                })
+                .collect();
+        }
+        Ok(points)
+    }
+
+    /// Every run of the sweep with its seed. The seed depends only on the
+    /// sweep seed, the values of the point and the number of the run, so it
+    /// doesn't change when points are added or reordered
+    pub fn jobs(&self) -> Result<Vec<(Experiment, u64)>, anyhow::Error> {
+        let mut jobs = Vec::new();
+        for point in self.points()? {
+            let mut experiment = self.base.clone();
+            for (path, value) in point.iter() {
+                set(&mut experiment, path, value.clone())?;
+            }
+            let mut experiment: Experiment = serde_json::from_value(experiment)?;
+            experiment.trace = None;
+            // sorted, the order the values are given in doesn't matter
+            let point: BTreeMap<_, _> = point.iter().collect();
+            let point = hash(serde_json::to_string(&point)?.as_bytes());
+            for run in 0..self.runs {
+                let seed = mix(mix(self.seed ^ point).wrapping_add(run));
+                jobs.push((experiment.clone(), seed));
+            }
+        }
+        Ok(jobs)
+    }
+
+    /// Runs everything that isn't in the output yet on all the cores, or on
+    /// `threads` of them
+    pub fn run(&self, threads: Option<usize>) -> Result<(), anyhow::Error> {
+        let done = read_seeds(&self.output)?;
+        let jobs: Vec<_> = self
+            .jobs()?
+            .into_iter()
+            .filter(|(_, seed)| !done.contains(seed))
+            .collect();
+        log::info!("{} runs done, {} to go", done.len(), jobs.len());
+
+        let export = Mutex::new(Exporter::create(&self.output)?);
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads.unwrap_or(0))
+            .build()?;
+        pool.install(|| {
+            jobs.par_iter().try_for_each(|(experiment, seed)| {
+                let record = experiment.run_once(*seed)?;
+                println!("{}", record.summary());
+                export.lock().unwrap().write(&record)
+            })
+        })
+    }
+}
+
+#[test]
+fn sweep() {
+    let _ = env_logger::builder().try_init();
+
+    let dir = std::env::temp_dir().join(format!("radio-message-sweep-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let output = dir.join("sweep.jsonl");
+    let mut sweep: Sweep = serde_json::from_value(serde_json::json!({
+        "base": {
+            "scenario": { "type": "complete", "nodes_count": 5 },
+            "protocol": { "name": "gossip" }
+        },
+        "grid": {
+            "scenario.messages_count": [2, 4],
+            "protocol": [{ "name": "gossip" }, { "name": "dsdv" }]
+        },
+        "runs": 2,
+        "seed": 1,
+        "output": output
+    }))
+    .unwrap();
+
+    let jobs = sweep.jobs().unwrap();
+    assert_eq!(jobs.len(), 8);
+    let seeds: std::collections::HashSet<_> = jobs.iter().map(|(_, seed)| *seed).collect();
+    assert_eq!(seeds.len(), 8);
+
+    // interrupted after the first half
+    sweep.runs = 1;
+    sweep.run(Some(2)).unwrap();
+    assert_eq!(read_seeds(&output).unwrap().len(), 4);
+    sweep.runs = 2;
+    sweep.run(Some(2)).unwrap();
+    assert_eq!(read_seeds(&output).unwrap(), seeds);
+    let lines = std::fs::read_to_string(&output).unwrap().lines().count();
+    assert_eq!(lines, 8);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,260 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use ::rand::{rngs::StdRng, SeedableRng};
+use macroquad::prelude::*;
+
+use crate::model::*;
+use crate::replay::SHOWN;
+use crate::scenarios::moving::*;
+use crate::{fit, FONT, MARGIN};
+
+const PANEL: f32 = 300.;
+// time a frame may spend running the model, the rest is for drawing
+const BUDGET: Duration = Duration::from_millis(25);
+
+/// A moving scenario run shown while it goes
+pub struct Live<P: Protocol> {
+    run: MovingRun<P, StdRng>,
+    seed: u64,
+    // steps per second
+    rate: f64,
+    playing: bool,
+    // step the run should be at by now
+    target: f64,
+    // (step, node) of the recent broadcasts
+    broadcasts: VecDeque<(u32, u32)>,
+}
+
+impl<P: Protocol> Live<P> {
+    pub fn new(
+        params: &MovingModelParams,
+        protocol: P::Params,
+        seed: u64,
+    ) -> Result<Live<P>, anyhow::Error> {
+        let mut model =
+            Model::with_params(params.agents_count, protocol, StdRng::seed_from_u64(seed));
+        model.set_trace(Trace::memory());
+        let run = MovingRun::new(model, params)?;
+        let target = run.model.current_step() as f64;
+        Ok(Live {
+            run,
+            seed,
+            rate: 1000.,
+            playing: true,
+            target,
+            broadcasts: VecDeque::new(),
+        })
+    }
+
+    // steps a broadcast stays on the screen, at least half a second
+    fn shown(&self) -> u32 {
+        (SHOWN as f64).max(self.rate / 2.) as u32
+    }
+
+    pub fn update(&mut self) {
+        if is_key_pressed(KeyCode::Space) {
+            self.playing = !self.playing;
+        }
+        if is_key_pressed(KeyCode::Up) {
+            self.rate *= 2.;
+        }
+        if is_key_pressed(KeyCode::Down) {
+            self.rate = (self.rate / 2.).max(1.);
+        }
+        if !self.playing || self.run.finished() {
+            return;
+        }
+
+        self.target += self.rate * get_frame_time() as f64;
+        let start = Instant::now();
+        while (self.run.model.current_step() as f64) < self.target.floor()
+            && !self.run.finished()
+            && start.elapsed() < BUDGET
+        {
+            self.run.advance(self.target as u32);
+        }
+        // too slow for the rate, don't try to catch up later
+        self.target = self.target.min(self.run.model.current_step() as f64 + 1.);
+
+        let events = match self.run.model.trace_mut() {
+            Some(trace) => trace.drain(),
+            None => Vec::new(),
+        };
+        for event in events {
+            if let TraceEvent::Transmit {
+                step,
+                node,
+                to: None,
+                ..
+            } = event
+            {
+                self.broadcasts.push_back((step, node));
+            }
+        }
+        let step = self.run.model.current_step();
+        let shown = self.shown();
+        while let Some(&(at, _)) = self.broadcasts.front() {
+            if at + shown >= step {
+                break;
+            }
+            self.broadcasts.pop_front();
+        }
+    }
+
+    fn to_screen(&self, position: (f64, f64)) -> Vec2 {
+        let size = self.run.params().field_size;
+        let area = Rect::new(
+            MARGIN,
+            MARGIN,
+            screen_width() - 3. * MARGIN - PANEL,
+            screen_height() - 2. * MARGIN,
+        );
+        fit(((0., 0.), (size, size)), area, position)
+    }
+
+    pub fn draw(&self) {
+        let model = &self.run.model;
+        let params = self.run.params();
+        let step = model.current_step();
+        let agents = self.run.agents();
+
+        let corner = self.to_screen((0., 0.));
+        let side = self.to_screen((params.field_size, params.field_size)) - corner;
+        draw_rectangle(corner.x, corner.y, side.x, side.y, WHITE);
+
+        // the ones blocking the signal darker
+        for obstacle in params.obstacles.iter() {
+            let color = match obstacle.attenuation {
+                Some(_) => Color::new(0.5, 0.4, 0.3, 0.6),
+                None => Color::new(0.3, 0.2, 0.1, 0.9),
+            };
+            let corners = &obstacle.polygon;
+            for (i, &from) in corners.iter().enumerate() {
+                let a = self.to_screen(from);
+                let b = self.to_screen(corners[(i + 1) % corners.len()]);
+                draw_line(a.x, a.y, b.x, b.y, 3., color);
+            }
+        }
+
+        for (&(from, to), &prob) in model.conn.prob.iter() {
+            let back = model.conn.prob(to, from);
+            // draw the pairs with both directions there once
+            if from == to || (back > 0. && from > to) {
+                continue;
+            }
+            let a = self.to_screen(agents[from as usize].position.to_tuple());
+            let b = self.to_screen(agents[to as usize].position.to_tuple());
+            let color = if back > 0. {
+                Color::new(1. - prob, prob * 0.7, 0.2, 0.5)
+            } else {
+                Color::new(0.6, 0.2, 0.8, 0.5)
+            };
+            draw_line(a.x, a.y, b.x, b.y, 1., color);
+        }
+
+        let shown = self.shown();
+        let radios = self.run.radios();
+        for &(at, node) in self.broadcasts.iter() {
+            let range = self.to_screen((params.range(&radios[node as usize]), 0.)).x - corner.x;
+            let share = step.saturating_sub(at) as f32 / shown as f32;
+            let center = self.to_screen(agents[node as usize].position.to_tuple());
+            let color = Color::new(0.2, 0.4, 0.9, 1. - share);
+            draw_circle_lines(center.x, center.y, range * share, 1., color);
+        }
+
+        for (id, agent) in agents.iter().enumerate() {
+            let position = self.to_screen(agent.position.to_tuple());
+            let destination = self.to_screen(agent.destination.to_tuple());
+            draw_line(
+                position.x,
+                position.y,
+                destination.x,
+                destination.y,
+                1.,
+                Color::new(0.5, 0.5, 0.5, 0.3),
+            );
+            draw_circle_lines(destination.x, destination.y, 3., 1., GRAY);
+            let color = if model.alive(id as u32) {
+                DARKBLUE
+            } else {
+                LIGHTGRAY
+            };
+            draw_circle(position.x, position.y, 6., color);
+            draw_text(
+                &id.to_string(),
+                position.x + 8.,
+                position.y - 8.,
+                FONT,
+                DARKGRAY,
+            );
+        }
+
+        self.draw_panel();
+    }
+
+    fn draw_panel(&self) {
+        let model = &self.run.model;
+        let stats = &model.stats;
+        let optional = |value: Option<f64>| value.map_or("-".to_string(), |v| format!("{:.1}", v));
+        let state = if self.run.finished() {
+            "finished"
+        } else if self.playing {
+            "running"
+        } else {
+            "paused"
+        };
+        let lines = [
+            format!("{} seed {}", P::NAME, self.seed),
+            format!(
+                "step {} / {}",
+                model.current_step(),
+                self.run.params().steps_count
+            ),
+            format!("{} steps/s, {}", self.rate, state),
+            String::new(),
+            format!("delivered {} / {}", stats.delivered, stats.messages.len()),
+            format!("delivery ratio {:.2}", stats.delivery_ratio()),
+            format!("avg time {}", optional(stats.avg_delivery_time())),
+            format!(
+                "p50 {}",
+                optional(stats.latency_percentile(50.).map(f64::from))
+            ),
+            format!("avg hops {}", optional(stats.avg_hops())),
+            format!("duplicates {}", stats.duplicates),
+            String::new(),
+            format!("received {}", stats.total),
+            format!("broadcasts {}", stats.broadcasts),
+            format!("unicasts {}", stats.unicasts),
+            format!("bytes sent {}", stats.bytes_sent),
+            format!(
+                "data {} / control {}",
+                stats.data.transmissions, stats.control.transmissions
+            ),
+            format!("collisions {}", stats.collisions),
+            format!("backoffs {}", stats.backoffs),
+            format!("dropped {}", stats.dropped),
+            format!(
+                "one-way {} / unicasts {}",
+                stats.one_way, stats.one_way_unicasts
+            ),
+            format!("dead {}", stats.dead),
+            String::new(),
+            "space: pause, up/down: rate".to_string(),
+            "purple links work one way".to_string(),
+        ];
+        let left = screen_width() - MARGIN - PANEL;
+        for (i, line) in lines.iter().enumerate() {
+            draw_text(line, left, MARGIN + FONT * (i + 1) as f32, FONT, BLACK);
+        }
+    }
+}
+
+pub async fn watch<P: Protocol>(mut live: Live<P>) {
+    loop {
+        live.update();
+        clear_background(LIGHTGRAY);
+        live.draw();
+        next_frame().await
+    }
+}
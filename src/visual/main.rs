@@ -1,19 +1,88 @@
-#[path = "../model/protocols"]
-mod model;
+mod live;
+mod replay;
 
+pub use radio_message::*;
+
+use ::rand::Rng;
+use clap::{Parser, Subcommand};
+use live::*;
 use macroquad::prelude::*;
+use protocols::*;
+use replay::*;
+use scenarios::experiment::{ProtocolName, ProtocolParams};
+use scenarios::moving::MovingModelParams;
+use std::path::PathBuf;
+
+const MARGIN: f32 = 30.;
+const FONT: f32 = 20.;
+
+/// Shows what happens in the simulations
+#[derive(Parser)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
 
-#[macroquad::main("radio-message")]
-async fn main() {
-    loop {
-        clear_background(LIGHTGRAY);
+#[derive(Subcommand)]
+enum Command {
+    /// Plays back a trace written with --trace
+    ///
+    /// Space plays and pauses, up and down change the speed, left and right
+    /// step back and forth, the timeline at the bottom jumps to any step.
+    /// [ and ] pick the message to follow, j jumps to its request and
+    /// backspace follows all of them again.
+    Replay { trace: PathBuf },
+    /// Runs the moving scenario and shows it as it goes
+    ///
+    /// Space pauses, up and down change the steps per second.
+    Live {
+        #[arg(value_enum)]
+        protocol: ProtocolName,
+        #[command(flatten)]
+        params: Box<MovingModelParams>,
+    },
+}
 
-        draw_line(40.0, 40.0, 100.0, 200.0, 15.0, BLUE);
-        draw_rectangle(screen_width() / 2.0 - 60.0, 100.0, 120.0, 60.0, GREEN);
-        draw_circle(screen_width() - 30.0, screen_height() - 30.0, 15.0, YELLOW);
+// field coordinates into `area`, keeping the proportions
+fn fit(
+    ((left, top), (right, bottom)): ((f64, f64), (f64, f64)),
+    area: Rect,
+    (x, y): (f64, f64),
+) -> Vec2 {
+    let scale =
+        (area.w as f64 / (right - left).max(1e-9)).min(area.h as f64 / (bottom - top).max(1e-9));
+    vec2(
+        area.x + ((x - left) * scale) as f32,
+        area.y + ((y - top) * scale) as f32,
+    )
+}
 
-        draw_text("HELLO", 20.0, 20.0, 30.0, DARKGRAY);
+fn main() -> Result<(), anyhow::Error> {
+    env_logger::init();
 
-        next_frame().await
+    match Cli::parse().command {
+        Command::Replay { trace } => {
+            // read before the window opens, so a bad trace is just an error
+            let replay = Replay::load(trace)?;
+            macroquad::Window::new("radio-message", play(replay));
+        }
+        Command::Live { protocol, params } => {
+            let seed = params.seed.unwrap_or_else(|| ::rand::thread_rng().gen());
+            match protocol.params() {
+                ProtocolParams::Gossip(protocol) => macroquad::Window::new(
+                    "radio-message",
+                    watch(Live::<GossipNode>::new(&params, protocol, seed)?),
+                ),
+                ProtocolParams::Dsdv(protocol) => macroquad::Window::new(
+                    "radio-message",
+                    watch(Live::<DSDVNode>::new(&params, protocol, seed)?),
+                ),
+                ProtocolParams::Cbr(protocol) => macroquad::Window::new(
+                    "radio-message",
+                    watch(Live::<CBRNode>::new(&params, protocol, seed)?),
+                ),
+            }
+        }
     }
+    Ok(())
 }
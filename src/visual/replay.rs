@@ -0,0 +1,483 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::model::*;
+use crate::{fit, FONT, MARGIN};
+use macroquad::prelude::*;
+
+/// Steps a lost message and a broadcast stay on the screen for
+pub const SHOWN: u32 = 50;
+const TIMELINE: f32 = 40.;
+
+/// A message on a link
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Flight {
+    pub from: u32,
+    pub to: u32,
+    pub start: u32,
+    // none if the link lost it
+    pub at: Option<u32>,
+    // requested message it carries, none for control traffic
+    pub message: Option<u32>,
+}
+
+impl Flight {
+    pub fn end(&self) -> u32 {
+        self.at.unwrap_or(self.start + SHOWN)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Leg {
+    step: u32,
+    position: (f64, f64),
+    destination: (f64, f64),
+    speed: f64,
+}
+
+impl Leg {
+    fn position(&self, step: u32) -> (f64, f64) {
+        let (x, y) = self.position;
+        let (dx, dy) = (self.destination.0 - x, self.destination.1 - y);
+        let length = (dx * dx + dy * dy).sqrt();
+        let passed = self.speed * step.saturating_sub(self.step) as f64;
+        if passed >= length {
+            return self.destination;
+        }
+        (x + dx / length * passed, y + dy / length * passed)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Request {
+    pub step: u32,
+    pub from: u32,
+    pub to: u32,
+    // step and hops of the first delivery
+    pub delivered: Option<(u32, u32)>,
+}
+
+/// A trace indexed for jumping to any step
+pub struct Replay {
+    pub nodes: u32,
+    pub end: u32,
+    legs: Vec<Vec<Leg>>,
+    // steps every node went down (false) and up (true) at
+    states: Vec<Vec<(u32, bool)>>,
+    // sorted by start
+    flights: Vec<Flight>,
+    longest: u32,
+    // (step, node, message), sorted by step
+    broadcasts: Vec<(u32, u32, Option<u32>)>,
+    pub requests: BTreeMap<u32, Request>,
+    // corners of the field the nodes move in
+    pub bounds: ((f64, f64), (f64, f64)),
+}
+
+impl Replay {
+    pub fn load(path: impl AsRef<Path>) -> Result<Replay, anyhow::Error> {
+        let path = path.as_ref();
+        let file = std::fs::File::open(path)
+            .map_err(|err| anyhow::anyhow!("can't open {}: {}", path.display(), err))?;
+        Ok(Replay::new(read_trace(std::io::BufReader::new(file))?))
+    }
+
+    pub fn new(mut events: Vec<TraceEvent>) -> Replay {
+        // battery deaths are recorded at the step the node ran dry, which can
+        // be earlier than the step they were noticed at
+        events.sort_by_key(|event| event.step());
+        let nodes = events
+            .iter()
+            .map(|event| match *event {
+                TraceEvent::Request { from, to, .. } => from.max(to),
+                TraceEvent::Transmit { node, .. }
+                | TraceEvent::Deliver { node, .. }
+                | TraceEvent::Down { node, .. }
+                | TraceEvent::Up { node, .. }
+                | TraceEvent::Move { node, .. } => node,
+                TraceEvent::Link { from, to, .. } | TraceEvent::Receive { from, to, .. } => {
+                    from.max(to)
+                }
+            })
+            .max()
+            .map_or(0, |node| node + 1);
+        let mut replay = Replay {
+            nodes,
+            end: events.last().map_or(0, |event| event.step()),
+            legs: vec![Vec::new(); nodes as usize],
+            states: vec![Vec::new(); nodes as usize],
+            flights: Vec::new(),
+            longest: 0,
+            broadcasts: Vec::new(),
+            requests: BTreeMap::new(),
+            bounds: ((0., 0.), (1., 1.)),
+        };
+        let mut corners: Option<((f64, f64), (f64, f64))> = None;
+        // links are recorded right after the transmission they carry
+        let mut message = None;
+        for event in events {
+            match event {
+                TraceEvent::Request { step, id, from, to } => {
+                    replay.requests.insert(
+                        id,
+                        Request {
+                            step,
+                            from,
+                            to,
+                            delivered: None,
+                        },
+                    );
+                }
+                TraceEvent::Transmit {
+                    step,
+                    node,
+                    to,
+                    message: carried,
+                    ..
+                } => {
+                    message = carried;
+                    if to.is_none() {
+                        replay.broadcasts.push((step, node, carried));
+                    }
+                }
+                TraceEvent::Link { step, from, to, at } => {
+                    let flight = Flight {
+                        from,
+                        to,
+                        start: step,
+                        at,
+                        message,
+                    };
+                    replay.longest = replay.longest.max(flight.end() - step);
+                    replay.end = replay.end.max(flight.end());
+                    replay.flights.push(flight);
+                }
+                TraceEvent::Deliver { step, id, hops, .. } => {
+                    if let Some(request) = replay.requests.get_mut(&id) {
+                        request.delivered.get_or_insert((step, hops));
+                    }
+                }
+                TraceEvent::Down { step, node } => replay.states[node as usize].push((step, false)),
+                TraceEvent::Up { step, node } => replay.states[node as usize].push((step, true)),
+                TraceEvent::Move {
+                    step,
+                    node,
+                    position,
+                    destination,
+                    speed,
+                } => {
+                    for (x, y) in [position, destination] {
+                        let ((left, top), (right, bottom)) =
+                            corners.get_or_insert(((x, y), (x, y)));
+                        *left = left.min(x);
+                        *top = top.min(y);
+                        *right = right.max(x);
+                        *bottom = bottom.max(y);
+                    }
+                    replay.legs[node as usize].push(Leg {
+                        step,
+                        position,
+                        destination,
+                        speed,
+                    });
+                }
+                TraceEvent::Receive { .. } => {}
+            }
+        }
+        if let Some(corners) = corners {
+            replay.bounds = corners;
+        }
+        replay
+    }
+
+    /// Where the node is at `step`, nodes that never moved stand on a circle
+    pub fn position(&self, node: u32, step: u32) -> (f64, f64) {
+        let legs = &self.legs[node as usize];
+        let current = legs.partition_point(|leg| leg.step <= step);
+        match legs.get(current.saturating_sub(1)) {
+            Some(leg) => leg.position(step),
+            None => {
+                let ((left, top), (right, bottom)) = self.bounds;
+                let angle = std::f64::consts::TAU * node as f64 / self.nodes as f64;
+                (
+                    (left + right) / 2. + (right - left) * 0.4 * angle.cos(),
+                    (top + bottom) / 2. + (bottom - top) * 0.4 * angle.sin(),
+                )
+            }
+        }
+    }
+
+    pub fn up(&self, node: u32, step: u32) -> bool {
+        let states = &self.states[node as usize];
+        let current = states.partition_point(|(at, _)| *at <= step);
+        current == 0 || states[current - 1].1
+    }
+
+    /// Messages on the links at `step`, the lost ones for `SHOWN` steps
+    pub fn flights(&self, step: u32) -> impl Iterator<Item = &Flight> {
+        let first = self
+            .flights
+            .partition_point(|flight| flight.start + self.longest < step);
+        let last = self.flights.partition_point(|flight| flight.start <= step);
+        self.flights[first..last]
+            .iter()
+            .filter(move |flight| flight.end() >= step)
+    }
+
+    /// Broadcasts of the last `SHOWN` steps with the steps since them
+    pub fn broadcasts(&self, step: u32) -> impl Iterator<Item = (u32, u32, Option<u32>)> + '_ {
+        let first = self
+            .broadcasts
+            .partition_point(|(at, ..)| at + SHOWN < step);
+        let last = self.broadcasts.partition_point(|(at, ..)| *at <= step);
+        self.broadcasts[first..last]
+            .iter()
+            .map(move |&(at, node, message)| (step - at, node, message))
+    }
+}
+
+struct Player {
+    replay: Replay,
+    step: f64,
+    playing: bool,
+    // steps per second
+    speed: f64,
+    // message everything else is faded out for
+    focus: Option<u32>,
+}
+
+impl Player {
+    fn new(replay: Replay) -> Player {
+        Player {
+            replay,
+            step: 0.,
+            playing: false,
+            speed: 100.,
+            focus: None,
+        }
+    }
+
+    fn current(&self) -> u32 {
+        self.step as u32
+    }
+
+    fn seek(&mut self, step: f64) {
+        self.step = step.clamp(0., self.replay.end as f64);
+    }
+
+    fn update(&mut self) {
+        if is_key_pressed(KeyCode::Space) {
+            self.playing = !self.playing;
+        }
+        if is_key_pressed(KeyCode::Up) {
+            self.speed *= 2.;
+        }
+        if is_key_pressed(KeyCode::Down) {
+            self.speed = (self.speed / 2.).max(1.);
+        }
+        if is_key_pressed(KeyCode::Right) {
+            self.seek(self.step.floor() + 1.);
+        }
+        if is_key_pressed(KeyCode::Left) {
+            self.seek(self.step.ceil() - 1.);
+        }
+        let ids: Vec<u32> = self.replay.requests.keys().cloned().collect();
+        if is_key_pressed(KeyCode::RightBracket) {
+            self.focus = match self.focus {
+                Some(id) => ids.iter().find(|&&other| other > id).cloned(),
+                None => ids.first().cloned(),
+            };
+        }
+        if is_key_pressed(KeyCode::LeftBracket) {
+            self.focus = match self.focus {
+                Some(id) => ids.iter().rev().find(|&&other| other < id).cloned(),
+                None => ids.last().cloned(),
+            };
+        }
+        if is_key_pressed(KeyCode::Backspace) {
+            self.focus = None;
+        }
+        if let Some(request) = self.focus.and_then(|id| self.replay.requests.get(&id)) {
+            if is_key_pressed(KeyCode::J) {
+                self.seek(request.step as f64);
+            }
+        }
+        let (x, y) = mouse_position();
+        if is_mouse_button_down(MouseButton::Left) && y >= screen_height() - TIMELINE {
+            let share = ((x - MARGIN) / (screen_width() - 2. * MARGIN)).clamp(0., 1.);
+            self.seek(share as f64 * self.replay.end as f64);
+        }
+        if self.playing {
+            self.seek(self.step + self.speed * get_frame_time() as f64);
+            if self.step >= self.replay.end as f64 {
+                self.playing = false;
+            }
+        }
+    }
+
+    fn to_screen(&self, position: (f64, f64)) -> Vec2 {
+        let area = Rect::new(
+            MARGIN,
+            MARGIN + FONT * 2.,
+            screen_width() - 2. * MARGIN,
+            screen_height() - 2. * MARGIN - TIMELINE - FONT * 2.,
+        );
+        fit(self.replay.bounds, area, position)
+    }
+
+    fn faded(&self, message: Option<u32>, color: Color) -> Color {
+        match self.focus {
+            Some(focus) if message != Some(focus) => Color::new(color.r, color.g, color.b, 0.15),
+            _ => color,
+        }
+    }
+
+    fn draw(&self) {
+        let step = self.current();
+        let replay = &self.replay;
+
+        for (since, node, message) in replay.broadcasts(step) {
+            let center = self.to_screen(replay.position(node, step));
+            let share = since as f32 / SHOWN as f32;
+            let color = self.faded(message, Color::new(0.2, 0.4, 0.9, 1. - share));
+            draw_circle_lines(center.x, center.y, 8. + share * 40., 1., color);
+        }
+
+        for flight in replay.flights(step) {
+            let from = self.to_screen(replay.position(flight.from, step));
+            let to = self.to_screen(replay.position(flight.to, step));
+            let base = match (flight.at, flight.message) {
+                (None, _) => RED,
+                (Some(_), Some(_)) => ORANGE,
+                (Some(_), None) => GRAY,
+            };
+            let color = self.faded(flight.message, base);
+            draw_line(from.x, from.y, to.x, to.y, 1., color);
+            if let Some(at) = flight.at {
+                let share =
+                    (self.step as f32 - flight.start as f32) / (at - flight.start).max(1) as f32;
+                let dot = from + (to - from) * share.clamp(0., 1.);
+                draw_circle(dot.x, dot.y, 3., color);
+            }
+        }
+
+        let focused = self.focus.and_then(|id| replay.requests.get(&id));
+        for node in 0..replay.nodes {
+            let center = self.to_screen(replay.position(node, step));
+            let color = match focused {
+                _ if !replay.up(node, step) => WHITE,
+                Some(request) if request.from == node => GREEN,
+                Some(request) if request.to == node => RED,
+                _ => DARKBLUE,
+            };
+            draw_circle(center.x, center.y, 6., color);
+            draw_text(
+                &node.to_string(),
+                center.x + 8.,
+                center.y - 8.,
+                FONT,
+                DARKGRAY,
+            );
+        }
+
+        let mut status = format!(
+            "step {} / {}   {} steps/s{}",
+            step,
+            replay.end,
+            self.speed,
+            if self.playing { "" } else { "   paused" }
+        );
+        if let (Some(id), Some(request)) = (self.focus, focused) {
+            status += &format!(
+                "   message {}: {} -> {} requested at {}, ",
+                id, request.from, request.to, request.step
+            );
+            status += &match request.delivered {
+                Some((at, hops)) => format!("delivered at {} in {} hops", at, hops),
+                None => "never delivered".to_string(),
+            };
+        }
+        draw_text(&status, MARGIN, MARGIN, FONT, BLACK);
+
+        let top = screen_height() - TIMELINE;
+        let width = screen_width() - 2. * MARGIN;
+        let at = |step: u32| MARGIN + width * step as f32 / replay.end.max(1) as f32;
+        draw_rectangle(MARGIN, top + 10., width, TIMELINE - 20., WHITE);
+        for request in replay.requests.values() {
+            if focused.is_some_and(|focused| focused != request) {
+                continue;
+            }
+            draw_line(
+                at(request.step),
+                top + 10.,
+                at(request.step),
+                top + 20.,
+                1.,
+                GREEN,
+            );
+            if let Some((delivered, _)) = request.delivered {
+                draw_line(
+                    at(delivered),
+                    top + 20.,
+                    at(delivered),
+                    top + TIMELINE - 10.,
+                    1.,
+                    RED,
+                );
+            }
+        }
+        draw_rectangle(at(step) - 1., top + 5., 3., TIMELINE - 10., BLACK);
+    }
+}
+
+pub async fn play(replay: Replay) {
+    let mut player = Player::new(replay);
+    loop {
+        player.update();
+        clear_background(LIGHTGRAY);
+        player.draw();
+        next_frame().await
+    }
+}
+
+#[test]
+fn replay() {
+    let _ = env_logger::builder().try_init();
+
+    let trace = "\
+        m 0 0 0 0 10 0 0.5\n\
+        m 0 1 10 10 10 10 0\n\
+        q 0 0 0 1\n\
+        t 2 0 * 20 0\n\
+        l 2 0 1 52\n\
+        l 2 0 2 -\n\
+        k 10 2\n\
+        r 52 0 1 0 +\n\
+        d 52 1 0 1 +\n\
+        u 60 2\n";
+    let replay = Replay::new(read_trace(trace.as_bytes()).unwrap());
+    assert_eq!(replay.nodes, 3);
+    assert_eq!(replay.end, 60);
+    assert_eq!(replay.bounds, ((0., 0.), (10., 10.)));
+
+    assert_eq!(replay.position(0, 4), (2., 0.));
+    assert_eq!(replay.position(0, 100), (10., 0.));
+    assert_eq!(replay.position(1, 30), (10., 10.));
+    // never moved, on a circle around the middle
+    let (x, y) = replay.position(2, 30);
+    assert!(((x - 5.).hypot(y - 5.) - 4.).abs() < 1e-9);
+
+    assert!(replay.up(2, 9));
+    assert!(!replay.up(2, 10));
+    assert!(replay.up(2, 60));
+
+    let flights: Vec<_> = replay.flights(30).collect();
+    assert_eq!(flights.len(), 2);
+    assert!(flights.iter().all(|flight| flight.message == Some(0)));
+    assert_eq!(replay.flights(53).count(), 0);
+    assert_eq!(
+        replay.broadcasts(30).collect::<Vec<_>>(),
+        vec![(28, 0, Some(0))]
+    );
+    assert_eq!(replay.requests[&0].delivered, Some((52, 1)));
+}